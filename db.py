@@ -55,6 +55,9 @@ class Product(Base):
     availability = Column(String)
     scraped = Column(DateTime)
     seller = Column(String)
+    # Filled in by the API's background job from the pack size.
+    price_per_unit = Column(Float)
+    price_unit = Column(String)
 
 
 class ProductScrapeStatus(Base):
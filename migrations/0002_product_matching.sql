@@ -0,0 +1,22 @@
+-- Links each seller's listing to a canonical product shared across sellers.
+CREATE TABLE IF NOT EXISTS canonical_product (
+    id BIGSERIAL PRIMARY KEY,
+    gtin BIGINT UNIQUE,
+    name TEXT NOT NULL,
+    brand TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS product_match (
+    seller TEXT NOT NULL,
+    sku BIGINT NOT NULL,
+    canonical_product_id BIGINT NOT NULL REFERENCES canonical_product (id) ON DELETE CASCADE,
+    -- 'gtin' or 'similarity'
+    method TEXT NOT NULL,
+    confidence DOUBLE PRECISION NOT NULL,
+    matched_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (seller, sku)
+);
+
+CREATE INDEX IF NOT EXISTS product_match_canonical_idx ON product_match (canonical_product_id);
+CREATE INDEX IF NOT EXISTS product_seller_sku_scraped_idx ON product (seller, sku, scraped DESC);
@@ -0,0 +1,10 @@
+-- Price normalised by pack size: per kg, per litre or per item.
+ALTER TABLE product ADD COLUMN IF NOT EXISTS price_per_unit DOUBLE PRECISION;
+ALTER TABLE product ADD COLUMN IF NOT EXISTS price_unit TEXT;
+
+-- Highest product.id each background job has processed, so jobs only look
+-- at rows scraped since their last run.
+CREATE TABLE IF NOT EXISTS job_watermark (
+    job TEXT PRIMARY KEY,
+    last_id BIGINT NOT NULL
+);
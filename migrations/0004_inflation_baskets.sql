@@ -0,0 +1,20 @@
+-- User-defined baskets for the weighted, chain-linked inflation index.
+CREATE TABLE IF NOT EXISTS inflation_basket (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+-- Each item is either every listing of a GTIN or one seller's listing.
+CREATE TABLE IF NOT EXISTS inflation_basket_item (
+    id BIGSERIAL PRIMARY KEY,
+    basket_id BIGINT NOT NULL REFERENCES inflation_basket (id) ON DELETE CASCADE,
+    gtin BIGINT,
+    seller TEXT,
+    sku BIGINT,
+    weight DOUBLE PRECISION NOT NULL CHECK (weight > 0),
+    CHECK (gtin IS NOT NULL OR (seller IS NOT NULL AND sku IS NOT NULL))
+);
+
+CREATE INDEX IF NOT EXISTS inflation_basket_item_basket_idx ON inflation_basket_item (basket_id);
+CREATE INDEX IF NOT EXISTS product_gtin_idx ON product (gtin);
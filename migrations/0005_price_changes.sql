@@ -0,0 +1,19 @@
+-- Daily price change per listing, kept up to date by the inflation refresh
+-- job so the inflation index doesn't have to window over all of product.
+-- Each row compares a listing's latest price on `day` with its latest price
+-- on an earlier day.
+CREATE TABLE IF NOT EXISTS price_change (
+    seller TEXT NOT NULL,
+    sku BIGINT NOT NULL,
+    day TIMESTAMP NOT NULL,
+    from_date TIMESTAMP NOT NULL,
+    to_date TIMESTAMP NOT NULL,
+    increase DOUBLE PRECISION NOT NULL,
+    years DOUBLE PRECISION NOT NULL CHECK (years > 0),
+    name TEXT NOT NULL,
+    brand TEXT,
+    category TEXT NOT NULL,
+    PRIMARY KEY (seller, sku, day)
+);
+
+CREATE INDEX IF NOT EXISTS price_change_day_idx ON price_change (day);
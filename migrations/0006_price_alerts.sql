@@ -0,0 +1,43 @@
+-- A user's watch on one listing. Any combination of conditions may be set;
+-- each one fires when a new scrape crosses it.
+CREATE TABLE IF NOT EXISTS price_watch (
+    id BIGSERIAL PRIMARY KEY,
+    users_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    seller TEXT NOT NULL,
+    sku BIGINT NOT NULL,
+    below_price DOUBLE PRECISION CHECK (below_price > 0),
+    change_percent DOUBLE PRECISION CHECK (change_percent > 0),
+    out_of_stock BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    CHECK (below_price IS NOT NULL OR change_percent IS NOT NULL OR out_of_stock)
+);
+
+CREATE INDEX IF NOT EXISTS price_watch_listing_idx ON price_watch (seller, sku);
+CREATE INDEX IF NOT EXISTS price_watch_users_idx ON price_watch (users_id);
+
+-- Where a user's alerts are POSTed. Payloads are signed with `secret`.
+CREATE TABLE IF NOT EXISTS alert_webhook (
+    users_id BIGINT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL
+);
+
+-- One row per alert, doubling as the delivery queue and the delivery log.
+CREATE TABLE IF NOT EXISTS alert_delivery (
+    id BIGSERIAL PRIMARY KEY,
+    watch_id BIGINT NOT NULL REFERENCES price_watch (id) ON DELETE CASCADE,
+    product_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    -- 'pending', 'delivered' or 'failed' once retries run out
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    response_status INTEGER,
+    last_error TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    delivered_at TIMESTAMP,
+    UNIQUE (watch_id, product_id, kind)
+);
+
+CREATE INDEX IF NOT EXISTS alert_delivery_pending_idx ON alert_delivery (next_attempt_at) WHERE status = 'pending';
@@ -0,0 +1,8 @@
+-- Users who want a periodic email digest of price changes.
+CREATE TABLE IF NOT EXISTS digest_subscription (
+    users_id BIGINT PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    email TEXT NOT NULL,
+    -- 'daily' or 'weekly'
+    frequency TEXT NOT NULL,
+    last_sent_at TIMESTAMP
+);
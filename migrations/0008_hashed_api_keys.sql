@@ -0,0 +1,25 @@
+-- API keys are stored as a SHA-256 hash plus a short prefix for display.
+-- Existing UUID keys keep working: their hash is taken over the lowercase
+-- hyphenated form, which is how they're normalised before lookup.
+ALTER TABLE api_key ADD COLUMN IF NOT EXISTS key_hash TEXT;
+ALTER TABLE api_key ADD COLUMN IF NOT EXISTS prefix TEXT;
+ALTER TABLE api_key ADD COLUMN IF NOT EXISTS label TEXT NOT NULL DEFAULT '';
+ALTER TABLE api_key ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE api_key ADD COLUMN IF NOT EXISTS last_used_at TIMESTAMP;
+ALTER TABLE api_key ADD COLUMN IF NOT EXISTS revoked_at TIMESTAMP;
+
+UPDATE api_key
+SET key_hash = encode(sha256(convert_to(key::text, 'UTF8')), 'hex'),
+    prefix = left(key::text, 8)
+WHERE key_hash IS NULL;
+
+ALTER TABLE api_key ALTER COLUMN key_hash SET NOT NULL;
+ALTER TABLE api_key ALTER COLUMN prefix SET NOT NULL;
+-- The plaintext column is no longer read or written. It stays, nullable,
+-- until no deployed release reads it, so rolling back doesn't lock out
+-- existing keys; it's dropped then in a migration of its own.
+ALTER TABLE api_key ALTER COLUMN key DROP NOT NULL;
+ALTER TABLE api_key ALTER COLUMN key DROP DEFAULT;
+
+CREATE UNIQUE INDEX IF NOT EXISTS api_key_key_hash_idx ON api_key (key_hash);
+CREATE INDEX IF NOT EXISTS api_key_users_idx ON api_key (users_id);
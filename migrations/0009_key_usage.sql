@@ -0,0 +1,14 @@
+-- Optional limit on how many credits one key may use per credits period,
+-- on top of the user's allowance.
+ALTER TABLE api_key ADD COLUMN IF NOT EXISTS credit_cap INTEGER CHECK (credit_cap > 0);
+
+-- Calls per key, endpoint and day within each credits period.
+CREATE TABLE IF NOT EXISTS api_usage (
+    api_key_id BIGINT NOT NULL REFERENCES api_key (id) ON DELETE CASCADE,
+    credits_period_id BIGINT NOT NULL REFERENCES credits_period (id) ON DELETE CASCADE,
+    -- Method and route pattern, e.g. "GET /api/products/:product_id"
+    endpoint TEXT NOT NULL,
+    day DATE NOT NULL,
+    calls INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (credits_period_id, api_key_id, endpoint, day)
+);
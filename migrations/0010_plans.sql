@@ -0,0 +1,17 @@
+-- Subscription plans and the credits each one gets per monthly period.
+CREATE TABLE IF NOT EXISTS plan (
+    name TEXT PRIMARY KEY,
+    monthly_credits INTEGER NOT NULL CHECK (monthly_credits > 0)
+);
+
+INSERT INTO plan (name, monthly_credits) VALUES
+    ('free', 1000),
+    ('pro', 50000),
+    ('enterprise', 1000000)
+ON CONFLICT (name) DO NOTHING;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS plan TEXT NOT NULL DEFAULT 'free' REFERENCES plan (name);
+
+-- Periods are created on demand when a month rolls over; this stops two
+-- concurrent requests from creating the same one twice.
+CREATE UNIQUE INDEX IF NOT EXISTS credits_period_users_start_idx ON credits_period (users_id, start_date);
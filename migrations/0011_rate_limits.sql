@@ -0,0 +1,7 @@
+-- Token bucket per API key: up to `rate_limit_burst` calls at once,
+-- refilled at `rate_limit_per_minute`.
+ALTER TABLE plan ADD COLUMN IF NOT EXISTS rate_limit_per_minute INTEGER NOT NULL DEFAULT 60 CHECK (rate_limit_per_minute > 0);
+ALTER TABLE plan ADD COLUMN IF NOT EXISTS rate_limit_burst INTEGER NOT NULL DEFAULT 20 CHECK (rate_limit_burst > 0);
+
+UPDATE plan SET rate_limit_per_minute = 600, rate_limit_burst = 100 WHERE name = 'pro';
+UPDATE plan SET rate_limit_per_minute = 6000, rate_limit_burst = 500 WHERE name = 'enterprise';
@@ -0,0 +1,9 @@
+-- Login sessions, kept here so they survive restarts and are shared by
+-- every instance.
+CREATE TABLE IF NOT EXISTS session (
+    id UUID PRIMARY KEY,
+    data JSONB NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS session_expires_at_idx ON session (expires_at);
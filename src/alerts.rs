@@ -0,0 +1,930 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+use axum::{
+    extract::Path,
+    extract::rejection::JsonRejection,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+    extract::State,
+};
+use axum_login::AuthUser;
+use chrono::{NaiveDateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use sha2::Sha256;
+use sqlx::{types::Json as SqlJson, PgPool};
+use tokio::time::{interval, Duration};
+use uuid::Uuid;
+
+use crate::auth::AuthSession;
+use crate::basket::IN_STOCK;
+use crate::config::Config;
+use crate::db::{get_watermark, set_watermark};
+use crate::error::{bad_request, AppError};
+
+const WATERMARK_JOB: &str = "price_alerts";
+const BATCH_SIZE: i64 = 5000;
+// Deliveries are retried with exponential backoff, starting at a minute,
+// until this many attempts have failed.
+const MAX_ATTEMPTS: i32 = 6;
+const RETRY_BASE_SECS: f64 = 60.0;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_BATCH: i64 = 100;
+
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct Watch {
+    pub id: i64,
+    pub seller: String,
+    pub sku: i64,
+    pub below_price: Option<f64>,
+    pub change_percent: Option<f64>,
+    pub out_of_stock: bool,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Deserialize)]
+pub struct NewWatch {
+    seller: String,
+    sku: i64,
+    below_price: Option<f64>,
+    change_percent: Option<f64>,
+    #[serde(default)]
+    out_of_stock: bool,
+}
+
+#[derive(Deserialize)]
+pub struct NewWebhook {
+    url: String,
+}
+
+#[derive(Serialize)]
+pub struct Webhook {
+    pub url: String,
+    // Only returned when the webhook is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct Delivery {
+    pub id: i64,
+    pub watch_id: i64,
+    pub kind: String,
+    pub payload: SqlJson<Value>,
+    pub status: String,
+    pub attempts: i32,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+}
+
+// A new scrape of a watched listing, next to the scrape before it.
+#[derive(sqlx::FromRow)]
+struct WatchedScrape {
+    watch_id: i64,
+    product_id: i32,
+    seller: String,
+    sku: i64,
+    name: String,
+    price: f64,
+    availability: String,
+    url: String,
+    scraped: NaiveDateTime,
+    below_price: Option<f64>,
+    change_percent: Option<f64>,
+    out_of_stock: bool,
+    previous_price: Option<f64>,
+    previous_availability: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AlertPayload<'a> {
+    kind: &'static str,
+    watch_id: i64,
+    seller: &'a str,
+    sku: i64,
+    name: &'a str,
+    url: &'a str,
+    price: f64,
+    previous_price: Option<f64>,
+    availability: &'a str,
+    scraped: NaiveDateTime,
+}
+
+#[derive(sqlx::FromRow)]
+struct PendingDelivery {
+    id: i64,
+    attempts: i32,
+    payload: SqlJson<Value>,
+    url: Option<String>,
+    secret: Option<String>,
+}
+
+
+// Conditions only fire when a scrape crosses them, so a watch below its
+// threshold doesn't alert again on every scrape.
+fn triggered(scrape: &WatchedScrape) -> Vec<&'static str> {
+    let mut kinds = Vec::new();
+    if let Some(below_price) = scrape.below_price {
+        if scrape.price > 0.0 && scrape.price < below_price && scrape.previous_price.is_none_or(|previous| previous >= below_price) {
+            kinds.push("below_price");
+        }
+    }
+    if let (Some(change_percent), Some(previous)) = (scrape.change_percent, scrape.previous_price) {
+        // The tolerance stops float error missing exact changes, e.g. 2.00
+        // to 1.80 working out at 9.999...%.
+        let change = ((scrape.price / previous - 1.0) * 100.0).abs();
+        if previous > 0.0 && scrape.price > 0.0 && change >= change_percent - 1e-9 {
+            kinds.push("price_change");
+        }
+    }
+    if scrape.out_of_stock && scrape.availability != IN_STOCK && scrape.previous_availability.as_deref() == Some(IN_STOCK) {
+        kinds.push("out_of_stock");
+    }
+    kinds
+}
+
+// Queues alerts for rows scraped since the last run. Scrapes from before a
+// watch was created never alert. Returns how many rows were looked at.
+pub async fn evaluate_alerts(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let mut processed = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let last_id = get_watermark(&mut *tx, WATERMARK_JOB).await?;
+        let (next_id, count): (Option<i64>, i64) = sqlx::query_as(
+            "SELECT MAX(id)::int8, COUNT(*) FROM (
+                SELECT id FROM product WHERE id > $1 ORDER BY id LIMIT $2
+            ) batch"
+        )
+        .bind(last_id)
+        .bind(BATCH_SIZE)
+        .fetch_one(&mut *tx).await?;
+        let Some(next_id) = next_id else {
+            return Ok(processed)
+        };
+
+        let scrapes: Vec<WatchedScrape> = sqlx::query_as(
+            "SELECT
+                price_watch.id AS watch_id,
+                product.id AS product_id,
+                product.seller, product.sku, product.name, product.price, product.availability, product.url, product.scraped,
+                price_watch.below_price, price_watch.change_percent, price_watch.out_of_stock,
+                previous.price AS previous_price,
+                previous.availability AS previous_availability
+            FROM product
+            JOIN price_watch ON price_watch.seller = product.seller AND price_watch.sku = product.sku
+            LEFT JOIN LATERAL (
+                SELECT earlier.price, earlier.availability
+                FROM product earlier
+                WHERE earlier.seller = product.seller AND earlier.sku = product.sku AND earlier.scraped < product.scraped
+                ORDER BY earlier.scraped DESC
+                LIMIT 1
+            ) previous ON TRUE
+            WHERE product.id > $1 AND product.id <= $2 AND product.scraped >= price_watch.created_at"
+        )
+        .bind(last_id)
+        .bind(next_id)
+        .fetch_all(&mut *tx).await?;
+
+        for scrape in &scrapes {
+            for kind in triggered(scrape) {
+                let payload = AlertPayload {
+                    kind,
+                    watch_id: scrape.watch_id,
+                    seller: &scrape.seller,
+                    sku: scrape.sku,
+                    name: &scrape.name,
+                    url: &scrape.url,
+                    price: scrape.price,
+                    previous_price: scrape.previous_price,
+                    availability: &scrape.availability,
+                    scraped: scrape.scraped,
+                };
+                sqlx::query(
+                    "INSERT INTO alert_delivery (watch_id, product_id, kind, payload)
+                    VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (watch_id, product_id, kind) DO NOTHING"
+                )
+                .bind(scrape.watch_id)
+                .bind(scrape.product_id)
+                .bind(kind)
+                .bind(SqlJson(payload))
+                .execute(&mut *tx).await?;
+            }
+        }
+        set_watermark(&mut *tx, WATERMARK_JOB, next_id).await?;
+        tx.commit().await?;
+        processed += count as usize;
+    }
+}
+
+
+// Hex HMAC-SHA256 of "{timestamp}.{body}". Receivers should recompute it
+// and reject old timestamps to stop replays.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Addresses a webhook may be sent to unless private webhooks are allowed:
+// not loopback, link-local, RFC 1918, shared (CGNAT), unique local,
+// multicast or unspecified, so a webhook can't be used to reach the
+// network the server runs in.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80)
+}
+
+// Where a webhook's requests go: its host and the addresses that host
+// resolved to when the URL was checked.
+struct WebhookTarget {
+    host: String,
+    addresses: Vec<SocketAddr>,
+}
+
+// Checks a webhook URL is http(s) and resolves its host. Unless
+// `allow_private`, every address must be public. Returns why the URL isn't
+// acceptable.
+async fn resolve_webhook(url: &str, allow_private: bool) -> Result<WebhookTarget, String> {
+    let url = match reqwest::Url::parse(url) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => url,
+        _ => return Err("url must be an absolute http or https URL".to_string()),
+    };
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return Err("url must have a host".to_string())
+    };
+    // IPv6 literals come back bracketed.
+    let bare_host = host.trim_start_matches('[').trim_end_matches(']');
+    let addresses: Vec<SocketAddr> = match tokio::net::lookup_host((bare_host, port)).await {
+        Ok(addresses) => addresses.collect(),
+        Err(_) => return Err(format!("couldn't resolve {bare_host}")),
+    };
+    if addresses.is_empty() {
+        return Err(format!("couldn't resolve {bare_host}"))
+    }
+    if !allow_private && addresses.iter().any(|address| !is_public(address.ip())) {
+        return Err("url must not point at a loopback, link-local or private address".to_string())
+    }
+    Ok(WebhookTarget { host: host.to_string(), addresses })
+}
+
+// Redirects aren't followed, since they could lead anywhere regardless of
+// where the webhook was checked to point. The host is pinned to the
+// addresses that were checked, so it can't be resolved again to somewhere
+// else (DNS rebinding) before the request connects.
+fn webhook_client(target: &WebhookTarget) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve_to_addrs(&target.host, &target.addresses)
+        .build()
+        .expect("Failed to build webhook HTTP client")
+}
+
+// POSTs the payload and returns the response status, or why there was none.
+// The URL is checked again here because what its host resolves to may have
+// changed since the webhook was set.
+async fn send(allow_private: bool, delivery: &PendingDelivery) -> Result<u16, (Option<u16>, String)> {
+    let (Some(url), Some(secret)) = (&delivery.url, &delivery.secret) else {
+        return Err((None, "no webhook configured".to_string()))
+    };
+    let target = resolve_webhook(url, allow_private).await.map_err(|err| (None, err))?;
+    let body = serde_json::to_vec(&delivery.payload.0).unwrap();
+    let timestamp = Utc::now().timestamp();
+    let response = webhook_client(&target)
+        .post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Alert-Delivery", delivery.id.to_string())
+        .header("X-Alert-Timestamp", timestamp.to_string())
+        .header("X-Alert-Signature", format!("sha256={}", sign(secret, timestamp, &body)))
+        .body(body)
+        .send().await
+        .map_err(|err| (None, err.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(status.as_u16())
+    } else {
+        Err((Some(status.as_u16()), format!("webhook responded {status}")))
+    }
+}
+
+// The status a delivery is left in after its attempt number `attempts + 1`
+// fails, and how many seconds until it's tried again: a minute, doubling
+// each time, until MAX_ATTEMPTS have failed.
+fn after_failure(attempts: i32) -> (&'static str, f64) {
+    let status = if attempts + 1 >= MAX_ATTEMPTS { "failed" } else { "pending" };
+    (status, RETRY_BASE_SECS * 2f64.powi(attempts))
+}
+
+// Sends every delivery that is due. Returns how many were delivered.
+pub async fn deliver_alerts(allow_private: bool, pool: &PgPool) -> Result<usize, sqlx::Error> {
+    // Claimed deliveries are pushed back far enough that no other worker
+    // picks them up while this one is still sending the batch.
+    let claim_secs = DELIVERY_BATCH as f64 * WEBHOOK_TIMEOUT.as_secs_f64();
+    let pending: Vec<PendingDelivery> = sqlx::query_as(
+        "WITH claimed AS (
+            UPDATE alert_delivery SET next_attempt_at = NOW() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM alert_delivery
+                WHERE status = 'pending' AND next_attempt_at <= NOW()
+                ORDER BY next_attempt_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, watch_id, attempts, payload
+        )
+        SELECT claimed.id, claimed.attempts, claimed.payload, alert_webhook.url, alert_webhook.secret
+        FROM claimed
+        JOIN price_watch ON price_watch.id = claimed.watch_id
+        LEFT JOIN alert_webhook ON alert_webhook.users_id = price_watch.users_id
+        ORDER BY claimed.id"
+    )
+    .bind(DELIVERY_BATCH)
+    .bind(claim_secs)
+    .fetch_all(pool).await?;
+
+    let mut delivered = 0;
+    for delivery in pending {
+        match send(allow_private, &delivery).await {
+            Ok(response_status) => {
+                sqlx::query(
+                    "UPDATE alert_delivery
+                    SET status = 'delivered', attempts = attempts + 1, response_status = $2, last_error = NULL, delivered_at = NOW()
+                    WHERE id = $1"
+                )
+                .bind(delivery.id)
+                .bind(response_status as i32)
+                .execute(pool).await?;
+                delivered += 1;
+            }
+            Err((response_status, error)) => {
+                let (status, retry_secs) = after_failure(delivery.attempts);
+                sqlx::query(
+                    "UPDATE alert_delivery
+                    SET status = $2, attempts = $3, response_status = $4, last_error = $5,
+                        next_attempt_at = NOW() + make_interval(secs => $6)
+                    WHERE id = $1"
+                )
+                .bind(delivery.id)
+                .bind(status)
+                .bind(delivery.attempts + 1)
+                .bind(response_status.map(i32::from))
+                .bind(error)
+                .bind(retry_secs)
+                .execute(pool).await?;
+            }
+        }
+    }
+    Ok(delivered)
+}
+
+
+pub async fn run_periodically(pool: PgPool, allow_private_webhooks: bool, every: Duration) {
+    let mut ticker = interval(every);
+    loop {
+        ticker.tick().await;
+        match evaluate_alerts(&pool).await {
+            Ok(0) => {}
+            Ok(processed) => tracing::info!("price alerts evaluated for {} rows", processed),
+            Err(err) => tracing::error!("price alert evaluation failed: {}", err),
+        }
+        match deliver_alerts(allow_private_webhooks, &pool).await {
+            Ok(0) => {}
+            Ok(delivered) => tracing::info!("delivered {} price alerts", delivered),
+            Err(err) => tracing::error!("price alert delivery failed: {}", err),
+        }
+    }
+}
+
+
+pub async fn list_watches(auth_session: AuthSession, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let watches: Result<Vec<Watch>, sqlx::Error> = sqlx::query_as(
+        "SELECT id, seller, sku, below_price, change_percent, out_of_stock, created_at
+        FROM price_watch
+        WHERE users_id = $1
+        ORDER BY id"
+    )
+    .bind(user.id())
+    .fetch_all(&pool).await;
+    match watches {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(watches) => Json(watches).into_response(),
+    }
+}
+
+pub async fn create_watch(auth_session: AuthSession, State(pool): State<PgPool>, request: Result<Json<NewWatch>, JsonRejection>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let Json(watch) = match request {
+        Ok(watch) => watch,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    if watch.below_price.is_none() && watch.change_percent.is_none() && !watch.out_of_stock {
+        return bad_request("Set at least one of below_price, change_percent or out_of_stock".to_string())
+    }
+    if watch.below_price.is_some_and(|price| !(price > 0.0 && price.is_finite())) {
+        return bad_request("below_price must be positive".to_string())
+    }
+    if watch.change_percent.is_some_and(|percent| !(percent > 0.0 && percent.is_finite())) {
+        return bad_request("change_percent must be positive".to_string())
+    }
+
+    let exists: Result<bool, sqlx::Error> = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM product WHERE seller = $1 AND sku = $2)")
+        .bind(&watch.seller)
+        .bind(watch.sku)
+        .fetch_one(&pool).await;
+    match exists {
+        Err(err) => return AppError::from(err).into_response(),
+        Ok(false) => return AppError::NotFound.into_response(),
+        Ok(true) => {}
+    }
+    let created: Result<Watch, sqlx::Error> = sqlx::query_as(
+        "INSERT INTO price_watch (users_id, seller, sku, below_price, change_percent, out_of_stock)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, seller, sku, below_price, change_percent, out_of_stock, created_at"
+    )
+    .bind(user.id())
+    .bind(&watch.seller)
+    .bind(watch.sku)
+    .bind(watch.below_price)
+    .bind(watch.change_percent)
+    .bind(watch.out_of_stock)
+    .fetch_one(&pool).await;
+    match created {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(created) => (StatusCode::CREATED, Json(created)).into_response(),
+    }
+}
+
+pub async fn delete_watch(auth_session: AuthSession, Path(watch_id): Path<i64>, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let deleted = sqlx::query("DELETE FROM price_watch WHERE id = $1 AND users_id = $2")
+        .bind(watch_id)
+        .bind(user.id())
+        .execute(&pool).await;
+    match deleted {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(result) if result.rows_affected() == 0 => AppError::NotFound.into_response(),
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+pub async fn get_webhook(auth_session: AuthSession, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let url: Result<Option<String>, sqlx::Error> = sqlx::query_scalar("SELECT url FROM alert_webhook WHERE users_id = $1")
+        .bind(user.id())
+        .fetch_optional(&pool).await;
+    match url {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(None) => AppError::NotFound.into_response(),
+        Ok(Some(url)) => Json(Webhook { url, secret: None }).into_response(),
+    }
+}
+
+// Setting the webhook always issues a new signing secret, so this is also
+// how a secret is rotated.
+pub async fn put_webhook(
+    auth_session: AuthSession,
+    State(pool): State<PgPool>,
+    State(config): State<Arc<Config>>,
+    request: Result<Json<NewWebhook>, JsonRejection>,
+) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let Json(webhook) = match request {
+        Ok(webhook) => webhook,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    if let Err(err) = resolve_webhook(&webhook.url, config.allow_private_webhooks).await {
+        return bad_request(err)
+    }
+    let secret = format!("whsec_{}", Uuid::new_v4().simple());
+    let result = sqlx::query(
+        "INSERT INTO alert_webhook (users_id, url, secret) VALUES ($1, $2, $3)
+        ON CONFLICT (users_id) DO UPDATE SET url = EXCLUDED.url, secret = EXCLUDED.secret"
+    )
+    .bind(user.id())
+    .bind(&webhook.url)
+    .bind(&secret)
+    .execute(&pool).await;
+    match result {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(_) => Json(Webhook { url: webhook.url, secret: Some(secret) }).into_response(),
+    }
+}
+
+// The delivery log: the user's most recent alerts and how sending them went.
+pub async fn list_deliveries(auth_session: AuthSession, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let deliveries: Result<Vec<Delivery>, sqlx::Error> = sqlx::query_as(
+        "SELECT alert_delivery.id, alert_delivery.watch_id, alert_delivery.kind, alert_delivery.payload, alert_delivery.status,
+            alert_delivery.attempts, alert_delivery.response_status, alert_delivery.last_error,
+            alert_delivery.created_at, alert_delivery.delivered_at
+        FROM alert_delivery
+        JOIN price_watch ON price_watch.id = alert_delivery.watch_id
+        WHERE price_watch.users_id = $1
+        ORDER BY alert_delivery.id DESC
+        LIMIT 100"
+    )
+    .bind(user.id())
+    .fetch_all(&pool).await;
+    match deliveries {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(deliveries) => Json(deliveries).into_response(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use axum::{
+        body::Bytes,
+        http::{header::LOCATION, HeaderMap},
+        routing::post,
+        Router,
+    };
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+    // A webhook receiver on a local port that records every request and
+    // answers with `status`. Redirects point at a port nothing listens on.
+    async fn receiver(status: StatusCode) -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new().route("/hook", post({
+            let received = received.clone();
+            move |headers: HeaderMap, body: Bytes| async move {
+                received.lock().unwrap().push((headers, body));
+                (status, [(LOCATION, "http://127.0.0.1:9/elsewhere")])
+            }
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (format!("http://{address}/hook"), received)
+    }
+
+    fn delivery(url: &str) -> PendingDelivery {
+        PendingDelivery {
+            id: 7,
+            attempts: 0,
+            payload: SqlJson(serde_json::json!({ "kind": "below_price", "price": 1.5 })),
+            url: Some(url.to_string()),
+            secret: Some("whsec_test".to_string()),
+        }
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+        headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    const OUT_OF_STOCK: &str = "https://schema.org/OutOfStock";
+
+    // A scrape of a listing watched for `conditions` (below_price,
+    // change_percent, out_of_stock).
+    fn scrape(
+        conditions: (Option<f64>, Option<f64>, bool),
+        previous: (Option<f64>, Option<&str>),
+        price: f64,
+        availability: &str,
+    ) -> WatchedScrape {
+        let (below_price, change_percent, out_of_stock) = conditions;
+        WatchedScrape {
+            watch_id: 1,
+            product_id: 1,
+            seller: "tesco".to_string(),
+            sku: 1,
+            name: "Milk".to_string(),
+            price,
+            availability: availability.to_string(),
+            url: "https://example.com/milk".to_string(),
+            scraped: NaiveDateTime::default(),
+            below_price,
+            change_percent,
+            out_of_stock,
+            previous_price: previous.0,
+            previous_availability: previous.1.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn below_price_fires_when_a_scrape_crosses_it() {
+        let below = (Some(2.0), None, false);
+        let cases = [
+            // previous price, price, fires
+            (Some(2.5), 1.5, true),
+            (Some(2.0), 1.99, true),
+            (None, 1.5, true),
+            (Some(1.8), 1.5, false),
+            (Some(2.5), 2.0, false),
+            (Some(1.5), 2.5, false),
+            (Some(2.5), 0.0, false),
+        ];
+        for (previous_price, price, fires) in cases {
+            let kinds = triggered(&scrape(below, (previous_price, Some(IN_STOCK)), price, IN_STOCK));
+            assert_eq!(kinds == ["below_price"], fires, "{previous_price:?} -> {price}: {kinds:?}");
+        }
+    }
+
+    #[test]
+    fn change_percent_fires_both_ways() {
+        let change = (None, Some(10.0), false);
+        let cases = [
+            // previous price, price, fires
+            (Some(1.0), 1.2, true),
+            (Some(1.0), 0.8, true),
+            (Some(2.0), 1.8, true),
+            (Some(1.0), 1.1, true),
+            (Some(1.0), 1.05, false),
+            (Some(1.0), 0.95, false),
+            (Some(0.0), 1.0, false),
+            (None, 1.0, false),
+            (Some(1.0), 0.0, false),
+        ];
+        for (previous_price, price, fires) in cases {
+            let kinds = triggered(&scrape(change, (previous_price, Some(IN_STOCK)), price, IN_STOCK));
+            assert_eq!(kinds == ["price_change"], fires, "{previous_price:?} -> {price}: {kinds:?}");
+        }
+    }
+
+    #[test]
+    fn out_of_stock_fires_only_when_stock_runs_out() {
+        let out_of_stock = (None, None, true);
+        let cases = [
+            // previous availability, availability, fires
+            (Some(IN_STOCK), OUT_OF_STOCK, true),
+            (Some(IN_STOCK), "https://schema.org/Discontinued", true),
+            (Some(OUT_OF_STOCK), OUT_OF_STOCK, false),
+            (Some(OUT_OF_STOCK), IN_STOCK, false),
+            (Some(IN_STOCK), IN_STOCK, false),
+            (None, OUT_OF_STOCK, false),
+        ];
+        for (previous_availability, availability, fires) in cases {
+            let kinds = triggered(&scrape(out_of_stock, (Some(1.0), previous_availability), 1.0, availability));
+            assert_eq!(kinds == ["out_of_stock"], fires, "{previous_availability:?} -> {availability}: {kinds:?}");
+        }
+        // Unwatched conditions never fire.
+        assert!(triggered(&scrape((None, None, false), (Some(1.0), Some(IN_STOCK)), 1.0, OUT_OF_STOCK)).is_empty());
+    }
+
+    #[test]
+    fn every_crossed_condition_fires() {
+        let all = (Some(2.0), Some(10.0), true);
+        let kinds = triggered(&scrape(all, (Some(3.0), Some(IN_STOCK)), 1.5, OUT_OF_STOCK));
+        assert_eq!(kinds, ["below_price", "price_change", "out_of_stock"]);
+    }
+
+    #[test]
+    fn sign_is_hmac_sha256_of_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec_test", 1700000000, br#"{"kind":"below_price"}"#),
+            "d6182769109061d5ebff24bdad6dc500588b51b7022e13f311d8694bb20d9180",
+        );
+        assert_ne!(sign("whsec_test", 1700000001, br#"{"kind":"below_price"}"#), sign("whsec_test", 1700000000, br#"{"kind":"below_price"}"#));
+        assert_ne!(sign("whsec_other", 1700000000, br#"{"kind":"below_price"}"#), sign("whsec_test", 1700000000, br#"{"kind":"below_price"}"#));
+    }
+
+    #[test]
+    fn failures_back_off_exponentially_until_max_attempts() {
+        let expected = [
+            ("pending", 60.0),
+            ("pending", 120.0),
+            ("pending", 240.0),
+            ("pending", 480.0),
+            ("pending", 960.0),
+            ("failed", 1920.0),
+        ];
+        assert_eq!(expected.len(), MAX_ATTEMPTS as usize);
+        for (attempts, expected) in expected.into_iter().enumerate() {
+            assert_eq!(after_failure(attempts as i32), expected, "after attempt {}", attempts + 1);
+        }
+    }
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        for ip in ["93.184.216.34", "8.8.8.8", "2606:2800:220:1:248:1893:25c8:1946"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "172.31.255.255", "192.168.1.1", "169.254.169.254",
+            "100.64.0.1", "0.0.0.0", "255.255.255.255", "224.0.0.1",
+            "::1", "::", "fe80::1", "fd00::1", "::ffff:10.0.0.1", "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn private_webhook_urls_are_rejected_unless_allowed() {
+        for url in [
+            "http://127.0.0.1:8080/hook",
+            "http://localhost/hook",
+            "http://10.0.0.5/hook",
+            "https://172.20.1.1/hook",
+            "http://192.168.0.10/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:192.168.0.1]/hook",
+        ] {
+            assert!(resolve_webhook(url, false).await.is_err(), "{url}");
+            assert!(resolve_webhook(url, true).await.is_ok(), "{url}");
+        }
+        let target = resolve_webhook("https://93.184.216.34/hook", false).await.unwrap();
+        assert_eq!((target.host.as_str(), target.addresses), ("93.184.216.34", vec![SocketAddr::from(([93, 184, 216, 34], 443))]));
+        assert!(resolve_webhook("ftp://93.184.216.34/hook", true).await.is_err());
+        assert!(resolve_webhook("/hook", true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn send_signs_the_body() {
+        let (url, received) = receiver(StatusCode::OK).await;
+        assert_eq!(send(true, &delivery(&url)).await, Ok(200));
+
+        let received = received.lock().unwrap();
+        let (headers, body) = &received[0];
+        let timestamp: i64 = header(headers, "x-alert-timestamp").parse().unwrap();
+        assert!((Utc::now().timestamp() - timestamp).abs() < 60);
+        assert_eq!(header(headers, "x-alert-signature"), format!("sha256={}", sign("whsec_test", timestamp, body)));
+        assert_eq!(header(headers, "x-alert-delivery"), "7");
+        assert_eq!(serde_json::from_slice::<Value>(body).unwrap(), delivery(&url).payload.0);
+    }
+
+    #[tokio::test]
+    async fn send_reports_failures() {
+        let (url, _) = receiver(StatusCode::INTERNAL_SERVER_ERROR).await;
+        assert_eq!(
+            send(true, &delivery(&url)).await,
+            Err((Some(500), "webhook responded 500 Internal Server Error".to_string())),
+        );
+
+        // Redirects are reported, not followed.
+        let (url, _) = receiver(StatusCode::TEMPORARY_REDIRECT).await;
+        assert_eq!(send(true, &delivery(&url)).await.unwrap_err().0, Some(307));
+
+        let no_webhook = PendingDelivery { url: None, secret: None, ..delivery("") };
+        assert_eq!(send(true, &no_webhook).await, Err((None, "no webhook configured".to_string())));
+    }
+
+    #[tokio::test]
+    async fn requests_go_to_the_checked_address() {
+        let (url, received) = receiver(StatusCode::OK).await;
+        let address: SocketAddr = url.trim_start_matches("http://").trim_end_matches("/hook").parse().unwrap();
+        // This host doesn't resolve, so the request only arrives if it's
+        // sent to the pinned address.
+        let target = WebhookTarget { host: "webhook.invalid".to_string(), addresses: vec![address] };
+        let response = webhook_client(&target)
+            .post(format!("http://webhook.invalid:{}/hook", address.port()))
+            .send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let received = received.lock().unwrap();
+        assert_eq!(header(&received[0].0, "host"), format!("webhook.invalid:{}", address.port()));
+    }
+
+    #[tokio::test]
+    async fn send_refuses_private_addresses_unless_allowed() {
+        let (url, received) = receiver(StatusCode::OK).await;
+        let (status, _) = send(false, &delivery(&url)).await.unwrap_err();
+        assert_eq!(status, None);
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    // A user with a watch, a webhook at `url` and one pending delivery.
+    // Returns the user's and the delivery's ids.
+    async fn pending_delivery(url: &str, pool: &PgPool) -> (i64, i64) {
+        let users_id: i64 = sqlx::query_scalar("INSERT INTO users (username, password) VALUES ($1, '') RETURNING id")
+            .bind(format!("alerts-test-{}", Uuid::new_v4()))
+            .fetch_one(pool).await.unwrap();
+        let watch_id: i64 = sqlx::query_scalar("INSERT INTO price_watch (users_id, seller, sku, out_of_stock) VALUES ($1, 'test', 1, TRUE) RETURNING id")
+            .bind(users_id)
+            .fetch_one(pool).await.unwrap();
+        sqlx::query("INSERT INTO alert_webhook (users_id, url, secret) VALUES ($1, $2, 'whsec_test')")
+            .bind(users_id)
+            .bind(url)
+            .execute(pool).await.unwrap();
+        let delivery_id: i64 = sqlx::query_scalar(
+            "INSERT INTO alert_delivery (watch_id, product_id, kind, payload) VALUES ($1, 0, 'out_of_stock', '{}') RETURNING id"
+        )
+        .bind(watch_id)
+        .fetch_one(pool).await.unwrap();
+        (users_id, delivery_id)
+    }
+
+    async fn test_pool() -> PgPool {
+        let pool = PgPool::connect(&std::env::var("DATABASE_URL").expect("DATABASE_URL must be set")).await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        pool
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL pointing at a database"]
+    async fn deliveries_are_claimed_once_and_signed() {
+        let pool = test_pool().await;
+        let (url, received) = receiver(StatusCode::OK).await;
+        let (users_id, delivery_id) = pending_delivery(&url, &pool).await;
+
+        // Two workers at once still send it only once.
+        let (first, second) = tokio::join!(deliver_alerts(true, &pool), deliver_alerts(true, &pool));
+        assert_eq!(first.unwrap() + second.unwrap(), 1);
+
+        let (status, attempts, response_status): (String, i32, Option<i32>) =
+            sqlx::query_as("SELECT status, attempts, response_status FROM alert_delivery WHERE id = $1")
+                .bind(delivery_id)
+                .fetch_one(&pool).await.unwrap();
+        assert_eq!((status.as_str(), attempts, response_status), ("delivered", 1, Some(200)));
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            let (headers, body) = &received[0];
+            let timestamp: i64 = header(headers, "x-alert-timestamp").parse().unwrap();
+            assert_eq!(header(headers, "x-alert-signature"), format!("sha256={}", sign("whsec_test", timestamp, body)));
+        }
+
+        sqlx::query("DELETE FROM users WHERE id = $1").bind(users_id).execute(&pool).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL pointing at a database"]
+    async fn failed_deliveries_back_off_then_fail() {
+        let pool = test_pool().await;
+        let (url, received) = receiver(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let (users_id, delivery_id) = pending_delivery(&url, &pool).await;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            assert_eq!(deliver_alerts(true, &pool).await.unwrap(), 0);
+            let (status, attempts, response_status, last_error, retry_secs): (String, i32, Option<i32>, Option<String>, f64) = sqlx::query_as(
+                "SELECT status, attempts, response_status, last_error, EXTRACT(epoch FROM next_attempt_at - NOW())::float8
+                FROM alert_delivery WHERE id = $1"
+            )
+            .bind(delivery_id)
+            .fetch_one(&pool).await.unwrap();
+            let expected_status = if attempt < MAX_ATTEMPTS { "pending" } else { "failed" };
+            assert_eq!((status.as_str(), attempts, response_status), (expected_status, attempt, Some(500)));
+            assert_eq!(last_error.as_deref(), Some("webhook responded 500 Internal Server Error"));
+            let expected_secs = RETRY_BASE_SECS * 2f64.powi(attempt - 1);
+            assert!((retry_secs - expected_secs).abs() < 5.0, "attempt {attempt}: retry in {retry_secs}s, not {expected_secs}s");
+
+            // Not due yet, so the next run leaves it alone.
+            deliver_alerts(true, &pool).await.unwrap();
+            assert_eq!(received.lock().unwrap().len(), attempt as usize);
+            sqlx::query("UPDATE alert_delivery SET next_attempt_at = NOW() WHERE id = $1")
+                .bind(delivery_id)
+                .execute(&pool).await.unwrap();
+        }
+
+        // Failed deliveries aren't tried again.
+        deliver_alerts(true, &pool).await.unwrap();
+        assert_eq!(received.lock().unwrap().len(), MAX_ATTEMPTS as usize);
+
+        sqlx::query("DELETE FROM users WHERE id = $1").bind(users_id).execute(&pool).await.unwrap();
+    }
+}
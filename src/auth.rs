@@ -9,6 +9,7 @@ use axum::{
     http::StatusCode,
     Form,
     extract::Query,
+    extract::State,
     response::Html,
 };
 use axum_login::{AuthUser, AuthnBackend, UserId};
@@ -17,8 +18,6 @@ use serde::{Serialize, Deserialize};
 use sqlx::{FromRow, PgPool};
 use askama::Template;
 
-use crate::db::db_conn;
-
 #[derive(Clone, Serialize, Deserialize, FromRow)]
 pub struct User {
     id: i64,
@@ -109,7 +108,7 @@ impl AuthnBackend for Backend {
 // We use a type alias for convenience.
 //
 // Note that we've supplied our concrete backend here.
-type AuthSession = axum_login::AuthSession<Backend>;
+pub type AuthSession = axum_login::AuthSession<Backend>;
 
 
 
@@ -182,15 +181,14 @@ pub async fn get_register(Query(NextUrl { next }): Query<NextUrl>) -> impl IntoR
 }
 
 
-pub async fn post_register(mut auth_session: AuthSession, Form(creds): Form<Credentials>) -> impl IntoResponse {
+pub async fn post_register(_auth_session: AuthSession, State(pool): State<PgPool>, Form(creds): Form<Credentials>) -> impl IntoResponse {
     let password_hash = generate_hash(creds.password);
-    let pool = db_conn().await;
     let query_result = sqlx::query("INSERT INTO users (username, password) VALUES ($1, $2)")
         .bind(creds.username)
         .bind(password_hash)
         .execute(&pool).await;
-    if query_result.is_err() {
-        println!("{:?}", query_result.err());
+    if let Err(err) = query_result {
+        tracing::error!("registering user failed: {}", err);
         return Response::builder().body(RegisterTemplate {
             message: Some("Invalid credentials.".to_string()),
             next: creds.next,
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::rejection::JsonRejection,
+    response::{IntoResponse, Response},
+    Json,
+    extract::State,
+};
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+
+use crate::error::{bad_request, AppError};
+
+const MAX_ITEMS: usize = 100;
+pub const IN_STOCK: &str = "https://schema.org/InStock";
+
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ItemKey {
+    Gtin { gtin: i64 },
+    Listing { seller: String, sku: i64 },
+    Query { query: String },
+}
+
+fn default_quantity() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+pub struct BasketItem {
+    #[serde(flatten)]
+    key: ItemKey,
+    #[serde(default = "default_quantity")]
+    quantity: u32,
+}
+
+#[derive(Deserialize)]
+pub struct BasketRequest {
+    items: Vec<BasketItem>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Offer {
+    seller: String,
+    sku: i64,
+    name: String,
+    price: f64,
+    availability: String,
+}
+
+#[derive(Serialize)]
+pub struct BasketLine {
+    // Index of the item in the request.
+    pub item: usize,
+    pub seller: String,
+    pub sku: i64,
+    pub name: String,
+    pub price: f64,
+    pub quantity: u32,
+    pub line_total: f64,
+}
+
+#[derive(Serialize)]
+pub struct SellerBasket {
+    pub seller: String,
+    pub total: f64,
+    pub complete: bool,
+    pub missing: Vec<usize>,
+    pub out_of_stock: Vec<usize>,
+    pub lines: Vec<BasketLine>,
+}
+
+#[derive(Serialize)]
+pub struct SplitBasket {
+    pub total: f64,
+    // Items no seller has in stock.
+    pub unavailable: Vec<usize>,
+    pub lines: Vec<BasketLine>,
+}
+
+#[derive(Serialize)]
+pub struct BasketResponse {
+    pub sellers: Vec<SellerBasket>,
+    pub split: SplitBasket,
+}
+
+
+// Each seller's best current offer for an item identified by GTIN or by one
+// seller's listing, including listings linked through the product matcher.
+// In-stock listings win over cheaper out-of-stock ones.
+async fn offers_for_product(gtin: Option<i64>, listing: Option<(&str, i64)>, pool: &PgPool) -> Result<Vec<Offer>, sqlx::Error> {
+    let (seller, sku) = listing.unzip();
+    sqlx::query_as(
+        "SELECT DISTINCT ON (seller) seller, sku, name, price, availability FROM (
+            SELECT DISTINCT ON (seller, sku) seller, sku, name, price, availability
+            FROM product
+            WHERE gtin = $1 OR (seller = $2 AND sku = $3) OR (seller, sku) IN (
+                SELECT seller, sku FROM product_match WHERE canonical_product_id IN (
+                    SELECT id FROM canonical_product WHERE gtin = $1
+                    UNION
+                    SELECT canonical_product_id FROM product_match WHERE seller = $2 AND sku = $3
+                )
+            )
+            ORDER BY seller, sku, scraped DESC
+        ) latest
+        WHERE price > 0
+        ORDER BY seller, availability = $4 DESC, price"
+    )
+    .bind(gtin)
+    .bind(seller)
+    .bind(sku)
+    .bind(IN_STOCK)
+    .fetch_all(pool).await
+}
+
+#[derive(sqlx::FromRow)]
+struct QueryOffer {
+    // Index into the queries passed to `offers_for_queries`.
+    query: i64,
+    #[sqlx(flatten)]
+    offer: Offer,
+}
+
+// The most relevant listing at each seller for every free-text item, in one
+// query. Listings are ranked the same way as search's relevance sort.
+async fn offers_for_queries(queries: &[&str], pool: &PgPool) -> Result<Vec<Vec<Offer>>, sqlx::Error> {
+    let rows: Vec<QueryOffer> = sqlx::query_as(
+        "SELECT DISTINCT ON (queries.query, latest.seller)
+            (queries.query - 1)::int8 AS query, latest.seller, latest.sku, latest.name, latest.price, latest.availability
+        FROM UNNEST($1::text[]) WITH ORDINALITY AS queries (text, query)
+        CROSS JOIN LATERAL (
+            SELECT DISTINCT ON (seller, sku) seller, sku, name, price, availability,
+                ts_rank(search_vector, websearch_to_tsquery('english', queries.text)) + word_similarity(queries.text, name) AS relevance
+            FROM product
+            WHERE search_vector @@ websearch_to_tsquery('english', queries.text) OR queries.text <% name
+            ORDER BY seller, sku, scraped DESC
+        ) latest
+        ORDER BY queries.query, latest.seller, latest.relevance DESC NULLS LAST, latest.sku"
+    )
+    .bind(queries)
+    .fetch_all(pool).await?;
+
+    let mut offers: Vec<Vec<Offer>> = queries.iter().map(|_| Vec::new()).collect();
+    for row in rows {
+        offers[row.query as usize].push(row.offer);
+    }
+    Ok(offers)
+}
+
+
+fn line(item: usize, offer: &Offer, quantity: u32) -> BasketLine {
+    BasketLine {
+        item,
+        seller: offer.seller.clone(),
+        sku: offer.sku,
+        name: offer.name.clone(),
+        price: offer.price,
+        quantity,
+        line_total: offer.price * quantity as f64,
+    }
+}
+
+
+pub async fn price_basket(items: &[BasketItem], pool: &PgPool) -> Result<BasketResponse, sqlx::Error> {
+    // Walks product_seller_sku_scraped_idx one seller at a time rather than
+    // reading every row of product.
+    let sellers: Vec<String> = sqlx::query_scalar(
+        "WITH RECURSIVE sellers AS (
+            SELECT MIN(seller) AS seller FROM product
+            UNION ALL
+            SELECT (SELECT MIN(seller) FROM product WHERE seller > sellers.seller)
+            FROM sellers
+            WHERE sellers.seller IS NOT NULL
+        )
+        SELECT seller FROM sellers WHERE seller IS NOT NULL"
+    )
+    .fetch_all(pool).await?;
+    let queries: Vec<&str> = items
+        .iter()
+        .filter_map(|item| match &item.key {
+            ItemKey::Query { query } => Some(query.as_str()),
+            _ => None,
+        })
+        .collect();
+    let mut query_offers = offers_for_queries(&queries, pool).await?.into_iter();
+
+    let mut baskets: BTreeMap<String, SellerBasket> = sellers
+        .iter()
+        .map(|seller| (seller.clone(), SellerBasket {
+            seller: seller.clone(),
+            total: 0.0,
+            complete: true,
+            missing: Vec::new(),
+            out_of_stock: Vec::new(),
+            lines: Vec::new(),
+        }))
+        .collect();
+    let mut split = SplitBasket { total: 0.0, unavailable: Vec::new(), lines: Vec::new() };
+
+    for (i, item) in items.iter().enumerate() {
+        let offers = match &item.key {
+            ItemKey::Gtin { gtin } => offers_for_product(Some(*gtin), None, pool).await?,
+            ItemKey::Listing { seller, sku } => offers_for_product(None, Some((seller, *sku)), pool).await?,
+            ItemKey::Query { .. } => query_offers.next().unwrap_or_default(),
+        };
+
+        for basket in baskets.values_mut() {
+            match offers.iter().find(|offer| offer.seller == basket.seller) {
+                None => basket.missing.push(i),
+                Some(offer) if offer.availability != IN_STOCK => basket.out_of_stock.push(i),
+                Some(offer) => {
+                    let line = line(i, offer, item.quantity);
+                    basket.total += line.line_total;
+                    basket.lines.push(line);
+                }
+            }
+        }
+
+        let cheapest = offers
+            .iter()
+            .filter(|offer| offer.availability == IN_STOCK)
+            .min_by(|a, b| a.price.total_cmp(&b.price));
+        match cheapest {
+            None => split.unavailable.push(i),
+            Some(offer) => {
+                let line = line(i, offer, item.quantity);
+                split.total += line.line_total;
+                split.lines.push(line);
+            }
+        }
+    }
+
+    let mut sellers: Vec<SellerBasket> = baskets.into_values().collect();
+    for basket in sellers.iter_mut() {
+        basket.complete = basket.missing.is_empty() && basket.out_of_stock.is_empty();
+    }
+    // Sellers who can fill the whole basket first, cheapest first.
+    sellers.sort_by(|a, b| b.complete.cmp(&a.complete).then(a.total.total_cmp(&b.total)));
+    Ok(BasketResponse { sellers, split })
+}
+
+
+pub async fn basket(State(pool): State<PgPool>, request: Result<Json<BasketRequest>, JsonRejection>) -> Response {
+    let Json(request) = match request {
+        Ok(request) => request,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    if request.items.is_empty() || request.items.len() > MAX_ITEMS {
+        return bad_request(format!("A basket must have between 1 and {MAX_ITEMS} items"))
+    }
+    if request.items.iter().any(|item| item.quantity == 0) {
+        return bad_request("Item quantities must be at least 1".to_string())
+    }
+    if request.items.iter().any(|item| matches!(&item.key, ItemKey::Query { query } if query.trim().is_empty())) {
+        return bad_request("Item queries must not be blank".to_string())
+    }
+
+    match price_basket(&request.items, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(response) => Json(response).into_response(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::StatusCode};
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    // Posts `body` to the basket handler. Only for baskets it rejects by size,
+    // quantity or blank query, since the pool can't connect.
+    async fn basket_with(body: serde_json::Value) -> (StatusCode, serde_json::Value) {
+        let pool = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+        let request = serde_json::from_value(body).unwrap();
+        let response = basket(State(pool), Ok(Json(request))).await;
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn invalid_baskets_are_rejected() {
+        let too_many: Vec<_> = (0..=MAX_ITEMS).map(|i| serde_json::json!({ "gtin": i })).collect();
+        for (body, message) in [
+            (serde_json::json!({ "items": [] }), "A basket must have between 1 and 100 items"),
+            (serde_json::json!({ "items": too_many }), "A basket must have between 1 and 100 items"),
+            (serde_json::json!({ "items": [{ "gtin": 1, "quantity": 0 }] }), "Item quantities must be at least 1"),
+            (serde_json::json!({ "items": [{ "gtin": 1 }, { "query": "" }] }), "Item queries must not be blank"),
+            (serde_json::json!({ "items": [{ "query": " \t " }] }), "Item queries must not be blank"),
+        ] {
+            let (status, body) = basket_with(body).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            assert_eq!(body["message"], message);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use axum::{
+    extract::Request,
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+
+tokio::task_local! {
+    // Set by the `request_id` middleware for the duration of each request.
+    static REQUEST_ID: Uuid;
+}
+
+// Errors returned by the JSON API. Every variant is sent as an `ErrorBody`
+// with a stable `code` clients can match on.
+#[derive(Debug)]
+pub enum AppError {
+    MissingKey,
+    InvalidKey,
+    // No session on an endpoint that needs a logged-in user.
+    NotLoggedIn,
+    CreditsExhausted,
+    KeyCapReached,
+    RateLimited,
+    BadRequest(String),
+    NotFound,
+    Internal(sqlx::Error),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    request_id: Option<String>,
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::MissingKey | AppError::InvalidKey | AppError::NotLoggedIn => StatusCode::UNAUTHORIZED,
+            AppError::CreditsExhausted | AppError::KeyCapReached => StatusCode::PAYMENT_REQUIRED,
+            AppError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::MissingKey => "missing_api_key",
+            AppError::InvalidKey => "invalid_api_key",
+            AppError::NotLoggedIn => "not_logged_in",
+            AppError::CreditsExhausted => "credits_exhausted",
+            AppError::KeyCapReached => "key_credit_cap_reached",
+            AppError::RateLimited => "rate_limited",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::NotFound => "not_found",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            AppError::MissingKey => "Send an API key in the header `Authorization: Bearer <key>`".to_string(),
+            AppError::InvalidKey => "The API key doesn't exist or has been revoked".to_string(),
+            AppError::NotLoggedIn => "Log in to use this endpoint".to_string(),
+            AppError::CreditsExhausted => "The credits for this period have been used up".to_string(),
+            AppError::KeyCapReached => "This key has reached its credit cap for this period".to_string(),
+            AppError::RateLimited => "Too many requests, try again after Retry-After seconds".to_string(),
+            AppError::BadRequest(detail) => detail.clone(),
+            AppError::NotFound => "Not found".to_string(),
+            // The details stay in the logs, under the same request id.
+            AppError::Internal(_) => "Something went wrong on our side".to_string(),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => AppError::NotFound,
+            err => AppError::Internal(err),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let request_id = REQUEST_ID.try_with(|id| id.to_string()).ok();
+        if let AppError::Internal(err) = &self {
+            tracing::error!("request {}: {}", request_id.as_deref().unwrap_or("-"), err);
+        }
+        let body = ErrorBody { code: self.code(), message: self.message(), request_id };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+pub fn bad_request(detail: String) -> Response {
+    AppError::BadRequest(detail).into_response()
+}
+
+
+// Gives each request an id, returned in `X-Request-Id` and in error bodies
+// so a failed call can be found in the logs.
+pub async fn request_id(req: Request, next: Next) -> Response {
+    let id = Uuid::new_v4();
+    let mut response = REQUEST_ID.scope(id, next.run(req)).await;
+    if let Ok(value) = HeaderValue::from_str(&id.to_string()) {
+        response.headers_mut().insert("X-Request-Id", value);
+    }
+    response
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+
+    use super::*;
+
+    async fn render(err: AppError) -> (StatusCode, serde_json::Value) {
+        let id = Uuid::new_v4();
+        let response = REQUEST_ID.scope(id, async { err.into_response() }).await;
+        let status = response.status();
+        let body: serde_json::Value = serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["request_id"], id.to_string());
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn errors_have_a_status_code_and_request_id() {
+        let (status, body) = render(AppError::NotLoggedIn).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::UNAUTHORIZED, Some("not_logged_in")));
+
+        let (status, body) = render(AppError::from(sqlx::Error::RowNotFound)).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::NOT_FOUND, Some("not_found")));
+
+        // Database errors are logged, not sent.
+        let (status, body) = render(AppError::from(sqlx::Error::PoolTimedOut)).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::INTERNAL_SERVER_ERROR, Some("internal_error")));
+        assert_eq!(body["message"], "Something went wrong on our side");
+    }
+}
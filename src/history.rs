@@ -0,0 +1,228 @@
+use axum::{
+    extract::Path,
+    extract::Query,
+    extract::rejection::{PathRejection, QueryRejection},
+    response::{IntoResponse, Response},
+    Json,
+    extract::State,
+};
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+
+use crate::error::{bad_request, AppError};
+
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl Interval {
+    // Used as the first argument to postgres' DATE_TRUNC.
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Interval::Day => "day",
+            Interval::Week => "week",
+            Interval::Month => "month",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    #[serde(default)]
+    interval: Interval,
+}
+
+#[derive(sqlx::FromRow)]
+struct HistoryRow {
+    seller: String,
+    sku: i64,
+    bucket: NaiveDateTime,
+    price: f64,
+    availability: String,
+    rating: Option<f64>,
+    review_count: i32,
+}
+
+#[derive(Serialize)]
+pub struct PricePoint {
+    pub date: NaiveDateTime,
+    pub price: f64,
+    pub availability: String,
+    pub rating: Option<f64>,
+    pub review_count: i32,
+}
+
+impl PricePoint {
+    // Review counts tick up on almost every scrape, so they are deliberately
+    // left out when deciding whether anything interesting changed.
+    fn same_as(&self, other: &PricePoint) -> bool {
+        self.price == other.price
+            && self.availability == other.availability
+            && self.rating == other.rating
+    }
+}
+
+#[derive(Serialize)]
+pub struct SellerHistory {
+    pub seller: String,
+    pub sku: i64,
+    pub points: Vec<PricePoint>,
+}
+
+#[derive(Serialize)]
+pub struct ProductHistory {
+    pub gtin: i64,
+    pub sellers: Vec<SellerHistory>,
+}
+
+
+pub async fn product_history(
+    gtin: i64,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    interval: Interval,
+    pool: &PgPool,
+) -> Result<Vec<SellerHistory>, sqlx::Error> {
+    // Keep the last scrape within each bucket, per seller listing.
+    let rows: Vec<HistoryRow> = sqlx::query_as(
+        "SELECT DISTINCT ON (seller, sku, DATE_TRUNC($2, scraped))
+            seller, sku, DATE_TRUNC($2, scraped) AS bucket, price, availability, rating, review_count
+        FROM product
+        WHERE gtin = $1
+            AND ($3::date IS NULL OR scraped >= $3)
+            AND ($4::date IS NULL OR scraped < $4 + 1)
+        ORDER BY seller, sku, DATE_TRUNC($2, scraped), scraped DESC"
+    )
+    .bind(gtin)
+    .bind(interval.as_sql())
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool).await?;
+    Ok(group_by_listing(rows))
+}
+
+// Groups rows ordered by listing and bucket into one history per listing,
+// dropping consecutive buckets where nothing changed.
+fn group_by_listing(rows: Vec<HistoryRow>) -> Vec<SellerHistory> {
+    let mut sellers: Vec<SellerHistory> = Vec::new();
+    for row in rows {
+        let point = PricePoint {
+            date: row.bucket,
+            price: row.price,
+            availability: row.availability,
+            rating: row.rating,
+            review_count: row.review_count,
+        };
+        match sellers.last_mut() {
+            Some(last) if last.seller == row.seller && last.sku == row.sku => {
+                if !last.points.last().is_some_and(|prev| prev.same_as(&point)) {
+                    last.points.push(point);
+                }
+            }
+            _ => sellers.push(SellerHistory {
+                seller: row.seller,
+                sku: row.sku,
+                points: vec![point],
+            }),
+        }
+    }
+    sellers
+}
+
+
+pub async fn history(
+    gtin: Result<Path<i64>, PathRejection>,
+    params: Result<Query<HistoryParams>, QueryRejection>,
+    State(pool): State<PgPool>,
+) -> Response {
+    let Path(gtin) = match gtin {
+        Ok(gtin) => gtin,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    let Query(params) = match params {
+        Ok(params) => params,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    let result = product_history(gtin, params.from, params.to, params.interval, &pool).await;
+
+    match result {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(sellers) if sellers.is_empty() => AppError::NotFound.into_response(),
+        Ok(sellers) => Json(ProductHistory { gtin, sellers }).into_response(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::Datelike;
+
+    use super::*;
+
+    fn row(seller: &str, sku: i64, day: u32, price: f64, availability: &str, rating: Option<f64>, review_count: i32) -> HistoryRow {
+        HistoryRow {
+            seller: seller.to_string(),
+            sku,
+            bucket: NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            price,
+            availability: availability.to_string(),
+            rating,
+            review_count,
+        }
+    }
+
+    fn days(history: &SellerHistory) -> Vec<u32> {
+        history.points.iter().map(|point| point.date.day()).collect()
+    }
+
+    #[test]
+    fn rows_are_grouped_per_listing() {
+        let sellers = group_by_listing(vec![
+            row("asda", 1, 1, 1.0, "InStock", None, 0),
+            row("asda", 1, 2, 1.2, "InStock", None, 0),
+            row("asda", 2, 1, 1.1, "InStock", None, 0),
+            row("tesco", 1, 1, 0.9, "InStock", None, 0),
+        ]);
+        let listings: Vec<(&str, i64, Vec<u32>)> = sellers.iter().map(|history| (history.seller.as_str(), history.sku, days(history))).collect();
+        assert_eq!(listings, [("asda", 1, vec![1, 2]), ("asda", 2, vec![1]), ("tesco", 1, vec![1])]);
+        assert!(group_by_listing(vec![]).is_empty());
+    }
+
+    #[test]
+    fn unchanged_buckets_are_collapsed() {
+        let sellers = group_by_listing(vec![
+            row("asda", 1, 1, 1.0, "InStock", Some(4.5), 10),
+            // Only the review count moved.
+            row("asda", 1, 2, 1.0, "InStock", Some(4.5), 12),
+            row("asda", 1, 3, 1.2, "InStock", Some(4.5), 12),
+            row("asda", 1, 4, 1.2, "OutOfStock", Some(4.5), 12),
+            row("asda", 1, 5, 1.2, "OutOfStock", Some(4.4), 13),
+            row("asda", 1, 6, 1.2, "OutOfStock", None, 13),
+            row("asda", 1, 7, 1.2, "OutOfStock", None, 13),
+            // Back to an earlier price still counts as a change.
+            row("asda", 1, 8, 1.0, "OutOfStock", None, 13),
+        ]);
+        assert_eq!(sellers.len(), 1);
+        assert_eq!(days(&sellers[0]), [1, 3, 4, 5, 6, 8]);
+        // The first point of a run is the one kept.
+        assert_eq!(sellers[0].points[0].review_count, 10);
+    }
+
+    #[test]
+    fn collapsing_doesnt_cross_listings() {
+        let sellers = group_by_listing(vec![
+            row("asda", 1, 1, 1.0, "InStock", None, 0),
+            row("tesco", 1, 2, 1.0, "InStock", None, 0),
+        ]);
+        assert_eq!(sellers.iter().map(days).collect::<Vec<_>>(), [vec![1], vec![2]]);
+    }
+}
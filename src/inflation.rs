@@ -0,0 +1,1187 @@
+use std::{
+    time::Instant,
+    collections::{BTreeMap, HashMap},
+};
+use askama::Template;
+use axum::{
+    extract::Query,
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::StatusCode,
+    response::{IntoResponse, Html, Response},
+    Json,
+    extract::State,
+};
+use chrono::{NaiveDateTime, NaiveDate};
+use serde::{Serialize, Deserialize};
+use sqlx::{
+    PgPool,
+    Pool,
+    Postgres,
+};
+use tokio::time::{interval, Duration};
+
+use crate::db::{get_watermark, set_watermark};
+use crate::error::{bad_request, AppError};
+
+const WATERMARK_JOB: &str = "price_changes";
+const BATCH_SIZE: i64 = 5000;
+const MAX_REGEX_LEN: usize = 200;
+// Regex filters run with this statement timeout, in milliseconds.
+const REGEX_TIMEOUT_MS: u32 = 2000;
+
+
+#[derive(sqlx::FromRow)]
+pub struct IndexBasket {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Template)]
+#[template(path="inflation.html")]
+struct InflationTemplate {
+    baskets: Vec<IndexBasket>,
+    // Shown instead of the chart when the page couldn't be loaded.
+    error: Option<String>,
+}
+
+pub async fn inflation(State(pool): State<PgPool>) -> Html<String> {
+    let inflation_template = match list_baskets(&pool).await {
+        Ok(baskets) => InflationTemplate { baskets, error: None },
+        Err(err) => {
+            tracing::error!("listing inflation baskets failed: {}", err);
+            InflationTemplate { baskets: Vec::new(), error: Some("Something went wrong loading the baskets".to_string()) }
+        }
+    };
+    Html(inflation_template.render().unwrap())
+}
+
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    Seller,
+    Brand,
+    Category,
+}
+
+impl GroupBy {
+    // Columns of price_change.
+    fn as_sql(&self) -> &'static str {
+        match self {
+            GroupBy::Seller => "seller",
+            GroupBy::Brand => "brand",
+            GroupBy::Category => "category",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchMode {
+    // Case-insensitive substring of the product name.
+    #[default]
+    Substring,
+    // websearch_to_tsquery syntax, e.g. `"orange juice" -smooth`.
+    FullText,
+    // Postgres regex, validated up front and run under a statement timeout.
+    Regex,
+}
+
+impl MatchMode {
+    pub fn from_param(param: &str) -> Option<MatchMode> {
+        match param {
+            "" | "substring" => Some(MatchMode::Substring),
+            "full_text" => Some(MatchMode::FullText),
+            "regex" => Some(MatchMode::Regex),
+            _ => None,
+        }
+    }
+}
+
+// Which product names an inflation series is calculated over. An empty
+// text matches every product.
+#[derive(Default)]
+pub struct NameFilter {
+    pub text: String,
+    pub mode: MatchMode,
+}
+
+impl NameFilter {
+    pub fn validate(&self) -> Result<(), FilterError> {
+        if self.mode != MatchMode::Regex {
+            return Ok(())
+        }
+        if self.text.len() > MAX_REGEX_LEN {
+            return Err(FilterError::TooLong)
+        }
+        // Stricter than Postgres (no backreferences or lookaround), which
+        // rules out the patterns that can take exponential time.
+        regex::RegexBuilder::new(&self.text)
+            .size_limit(1 << 20)
+            .build()
+            .map(|_| ())
+            .map_err(|err| FilterError::InvalidRegex(err.to_string()))
+    }
+
+    fn as_sql(&self) -> &'static str {
+        match self.mode {
+            MatchMode::Substring => "name ILIKE $1",
+            MatchMode::FullText => "($1 = '' OR to_tsvector('english', name) @@ websearch_to_tsquery('english', $1))",
+            MatchMode::Regex => "name ~* $1",
+        }
+    }
+
+    fn bind_value(&self) -> String {
+        match self.mode {
+            MatchMode::Substring => {
+                let escaped = self.text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+                format!("%{escaped}%")
+            }
+            MatchMode::FullText | MatchMode::Regex => self.text.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FilterError {
+    TooLong,
+    InvalidRegex(String),
+    Timeout,
+}
+
+impl FilterError {
+    // Errors Postgres raises because of the filter rather than a fault.
+    pub fn from_db(err: &sqlx::Error) -> Option<FilterError> {
+        let err = err.as_database_error()?;
+        match err.code()?.as_ref() {
+            // invalid_regular_expression
+            "2201B" => Some(FilterError::InvalidRegex(err.message().to_string())),
+            // query_canceled, which is what statement_timeout raises
+            "57014" => Some(FilterError::Timeout),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for FilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterError::TooLong => write!(f, "regex must be at most {MAX_REGEX_LEN} characters"),
+            FilterError::InvalidRegex(reason) => write!(f, "invalid regex: {reason}"),
+            FilterError::Timeout => write!(f, "regex took too long to run; try a simpler pattern"),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path="inflation_error.html")]
+struct InflationErrorTemplate {
+    message: String,
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    // Arithmetic mean of price changes.
+    #[default]
+    Mean,
+    Median,
+    // Mean after dropping `trim` of the changes from each end.
+    TrimmedMean,
+    // Geometric mean of price relatives.
+    Jevons,
+    // Geometric mean weighted by average expenditure share across the two
+    // periods; needs basket weights.
+    Tornqvist,
+}
+
+pub struct Methodology {
+    pub method: Method,
+    pub trim: f64,
+    // Price relatives above this factor, or below its inverse, are treated
+    // as mispriced scrapes and ignored.
+    pub outlier_factor: Option<f64>,
+}
+
+impl Default for Methodology {
+    fn default() -> Self {
+        Methodology { method: Method::Mean, trim: 0.1, outlier_factor: None }
+    }
+}
+
+impl Methodology {
+    fn is_outlier(&self, relative: f64) -> bool {
+        self.outlier_factor.is_some_and(|factor| relative > factor || relative < 1.0 / factor)
+    }
+
+    // How one day's per-listing rates are combined in SQL. `rate` is the
+    // linearly annualised daily change and `log_rate` its log equivalent.
+    fn aggregate_sql(&self) -> &'static str {
+        match self.method {
+            Method::Mean | Method::TrimmedMean | Method::Tornqvist => "1 + AVG(rate)",
+            Method::Median => "1 + PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY rate)",
+            Method::Jevons => "EXP(AVG(log_rate))",
+        }
+    }
+
+    fn sql_trim(&self) -> f64 {
+        if self.method == Method::TrimmedMean { self.trim } else { 0.0 }
+    }
+}
+
+pub type InflationGroup = (Option<String>, Vec<(NaiveDateTime, f64)>);
+
+
+// The second breadcrumb, e.g. "Food Cupboard" under "Groceries".
+const CATEGORY_SQL: &str = "COALESCE(
+    breadcrumbs_json_ld -> 'itemListElement' -> 1 -> 'item' ->> 'name',
+    breadcrumbs_json_ld -> 'itemListElement' -> 1 ->> 'name',
+    'Uncategorised'
+)";
+
+// Brings price_change up to date with rows scraped since the last run.
+// Each new scrape replaces its listing's change for that day if it's the
+// latest one, measured against the listing's last price on an earlier day.
+// Returns how many product rows were looked at.
+pub async fn refresh_price_changes(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let query = format!("
+        INSERT INTO price_change (seller, sku, day, from_date, to_date, increase, years, name, brand, category)
+        SELECT
+            latest.seller, latest.sku, latest.day,
+            previous.scraped,
+            latest.scraped,
+            latest.price / previous.price - 1,
+            EXTRACT(epoch FROM latest.scraped - previous.scraped) / (60 * 60 * 24 * 365.25),
+            latest.name, latest.brand, latest.category
+        FROM (
+            SELECT DISTINCT ON (seller, sku, DATE_TRUNC('day', scraped))
+                seller, sku, DATE_TRUNC('day', scraped) AS day, scraped, price,
+                COALESCE(name, '') AS name, brand, {CATEGORY_SQL} AS category
+            FROM product
+            WHERE id > $1 AND id <= $2 AND price > 0
+            ORDER BY seller, sku, DATE_TRUNC('day', scraped), scraped DESC
+        ) latest
+        CROSS JOIN LATERAL (
+            SELECT earlier.scraped, earlier.price
+            FROM product earlier
+            WHERE earlier.seller = latest.seller AND earlier.sku = latest.sku
+                AND earlier.price > 0 AND earlier.scraped < latest.day
+            ORDER BY earlier.scraped DESC
+            LIMIT 1
+        ) previous
+        ON CONFLICT (seller, sku, day) DO UPDATE SET
+            to_date = EXCLUDED.to_date,
+            increase = EXCLUDED.increase,
+            years = EXCLUDED.years,
+            name = EXCLUDED.name,
+            brand = EXCLUDED.brand,
+            category = EXCLUDED.category
+        WHERE price_change.to_date < EXCLUDED.to_date
+    ");
+
+    let mut processed = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let last_id = get_watermark(&mut *tx, WATERMARK_JOB).await?;
+        let (next_id, count): (Option<i64>, i64) = sqlx::query_as(
+            "SELECT MAX(id)::int8, COUNT(*) FROM (
+                SELECT id FROM product WHERE id > $1 ORDER BY id LIMIT $2
+            ) batch"
+        )
+        .bind(last_id)
+        .bind(BATCH_SIZE)
+        .fetch_one(&mut *tx).await?;
+        let Some(next_id) = next_id else {
+            return Ok(processed)
+        };
+
+        sqlx::query(&query)
+            .bind(last_id)
+            .bind(next_id)
+            .execute(&mut *tx).await?;
+        set_watermark(&mut *tx, WATERMARK_JOB, next_id).await?;
+        tx.commit().await?;
+        processed += count as usize;
+    }
+}
+
+
+pub async fn run_periodically(pool: PgPool, every: Duration) {
+    let mut ticker = interval(every);
+    loop {
+        ticker.tick().await;
+        match refresh_price_changes(&pool).await {
+            Ok(0) => {}
+            Ok(processed) => tracing::info!("price changes refreshed for {} rows", processed),
+            Err(err) => tracing::error!("price change refresh failed: {}", err),
+        }
+    }
+}
+
+pub async fn calc_inflation_rate2(pool: Pool<Postgres>, filter: &NameFilter, group_by: Option<GroupBy>, methodology: &Methodology) -> Result<Vec<InflationGroup>, sqlx::Error> {
+    let now = Instant::now();
+
+    let group_sql = group_by.map_or("NULL::text", |group_by| group_by.as_sql());
+    let aggregate_sql = methodology.aggregate_sql();
+    let filter_sql = filter.as_sql();
+    let query = format!("
+    SELECT
+        grp,
+        day,
+        {aggregate_sql},
+        DATE_TRUNC('day', MIN(start))
+    FROM
+        (
+        SELECT
+            *,
+            ROW_NUMBER() OVER (PARTITION BY grp, day ORDER BY rate) AS rn,
+            COUNT(*) OVER (PARTITION BY grp, day) AS n
+        FROM
+            (
+            SELECT
+                grp,
+                day,
+                increase,
+                increase / years / 365 AS rate,
+                LN(1 + increase) / (years * 365) AS log_rate,
+                MIN(from_date) OVER (PARTITION BY grp) AS start
+            FROM
+                (
+                SELECT *, {group_sql} AS grp
+                FROM price_change
+                WHERE {filter_sql}
+                ) t1
+            ) t2
+        WHERE $2::float8 IS NULL OR (1 + increase BETWEEN 1 / $2 AND $2)
+        ) t3
+    WHERE rn > FLOOR(n * $3) AND rn <= n - FLOOR(n * $3)
+    GROUP BY grp, day
+    ORDER BY grp, day
+    ");
+    let mut tx = pool.begin().await?;
+    if filter.mode == MatchMode::Regex {
+        sqlx::query(&format!("SET LOCAL statement_timeout = {REGEX_TIMEOUT_MS}"))
+            .execute(&mut *tx).await?;
+    }
+    let result: Vec<(Option<String>, NaiveDateTime, f64, NaiveDateTime)> = sqlx::query_as(&query)
+        .bind(filter.bind_value())
+        .bind(methodology.outlier_factor)
+        .bind(methodology.sql_trim())
+        .fetch_all(&mut *tx).await?;
+    tx.commit().await?;
+    tracing::debug!("inflation query done in {:.4?}", now.elapsed());
+
+    // Each series starts at 1.0 on the day of its earliest scrape, the one
+    // the first price change is measured from.
+    let mut groups: Vec<InflationGroup> = Vec::new();
+    for (group, dt, rate, start) in result {
+        match groups.last_mut() {
+            Some((last, rates)) if *last == group => rates.push((dt, rate)),
+            _ => groups.push((group, vec![(start, 1.0), (dt, rate)])),
+        }
+    }
+    // Without grouping there's always exactly one series, even if empty.
+    if group_by.is_none() && groups.is_empty() {
+        groups.push((None, Vec::new()));
+    }
+    for (_, inflation_data) in groups.iter_mut() {
+        *inflation_data = inflation_data.iter().scan(1.0, |state, x| {
+            *state *= x.1;
+            Some((x.0, *state))
+        }).collect();
+    }
+
+
+    tracing::debug!("inflation rate calculated in {:.4?}", now.elapsed());
+    Ok(groups)
+}
+
+
+fn error_fragment(message: String) -> Html<String> {
+    Html(InflationErrorTemplate { message }.render().unwrap())
+}
+
+pub async fn inflation_viz(Query(params): Query<HashMap<String, String>>, State(pool): State<PgPool>) -> Html<String> {
+    let is_table = params.contains_key("table");
+    let basket_id = params.get("basket_id").and_then(|id| id.parse::<i64>().ok());
+    let Some(mode) = MatchMode::from_param(params.get("match").map_or("", String::as_str)) else {
+        return error_fragment("Unknown match mode".to_string())
+    };
+    let filter = NameFilter { text: params.get("q").cloned().unwrap_or_default(), mode };
+    if let Err(err) = filter.validate() {
+        return error_fragment(err.to_string())
+    }
+    let result = match basket_id {
+        Some(basket_id) => calc_basket_index(basket_id, &Methodology::default(), &pool).await,
+        None => calc_inflation_rate2(pool, &filter, None, &Methodology::default()).await.map(|mut groups| groups.remove(0).1),
+    };
+    let inflation_data = match result {
+        Ok(inflation_data) => inflation_data,
+        Err(err) => {
+            return match FilterError::from_db(&err) {
+                Some(err) => error_fragment(err.to_string()),
+                None => {
+                    tracing::error!("inflation query failed: {}", err);
+                    error_fragment("Something went wrong calculating inflation".to_string())
+                }
+            }
+        }
+    };
+    let final_table: String = inflation_data
+        .iter()
+        .map(|(dt, val)| format!("<tr><td>{}</td><td>{:.3}</td></tr>", dt.date(), val))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let table_html = format!(r#"<table class="table table-sm">{final_table}</table>"#);
+
+    let (x, y): (Vec<NaiveDateTime>, Vec<f64>) = inflation_data.into_iter().unzip();
+    let x: Vec<String> = x.into_iter().map(|d| d.date().to_string()).collect();
+    let chart_html = format!(r#"
+    <div class="chart-container" style="position: relative; height: 70vh; width: 100vw;">
+        <canvas id="inflation-chart"></canvas>
+    </div>
+
+    <script>
+    var datasets = [{{
+        label: "inflation",
+        data: {y:?},
+        pointHitRadius: 10,
+        pointRadius: 0,
+        borderColor: "black",
+        backgroundColor: "black"
+
+    }}];
+    var labels = {x:?};
+    var chart_type = "line";
+    var data = {{
+        labels: labels,
+        datasets: datasets,    
+    }};
+
+    var config = {{
+        type: chart_type,
+        data: data,
+        options: {{
+            scales: {{
+                y: {{
+                    grace: "20%",
+                }},
+                x: {{
+                    type: 'time',
+                    grid: {{
+                        display: false
+                    }}
+                }}
+            }},
+            animation: {{
+                duration: 0,
+            }},
+            maintainAspectRatio: false
+        }}
+    }};
+    var InflationChart = new Chart(
+        document.getElementById('inflation-chart'),
+        config,
+    );
+    </script>
+    "#);
+
+    let output_html = if is_table {table_html} else {chart_html};
+    Html(format!(r#"<div id="inflation-viz">{output_html}</div>"#))
+}
+
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    #[default]
+    Cumulative,
+    Annualised,
+}
+
+// Where a series is anchored and which part of it to return.
+pub struct Baseline {
+    // Defaults to the first point in the window.
+    pub base_date: Option<NaiveDate>,
+    pub base_value: f64,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl Default for Baseline {
+    fn default() -> Self {
+        Baseline { base_date: None, base_value: 1.0, from: None, to: None }
+    }
+}
+
+#[derive(Debug)]
+pub enum BaselineError {
+    NoData { base_date: NaiveDate },
+    OutOfRange { group: Option<String>, base_date: NaiveDate, first: NaiveDate, last: NaiveDate },
+}
+
+impl std::fmt::Display for BaselineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BaselineError::NoData { base_date } => {
+                write!(f, "base_date {base_date} can't be used: there is no data in the requested window")
+            }
+            BaselineError::OutOfRange { group, base_date, first, last } => {
+                let group = group.as_ref().map_or(String::new(), |group| format!(" for {group}"));
+                write!(f, "base_date {base_date} is outside the available data{group} ({first} to {last})")
+            }
+        }
+    }
+}
+
+// Cuts a cumulative series down to the window and rescales it so it reads
+// `base_value` on the base date. A base date between two points takes the
+// value of the earlier one.
+pub fn apply_baseline(group: &Option<String>, series: Vec<(NaiveDateTime, f64)>, baseline: &Baseline) -> Result<Vec<(NaiveDateTime, f64)>, BaselineError> {
+    let series: Vec<(NaiveDateTime, f64)> = series
+        .into_iter()
+        .filter(|(dt, _)| baseline.from.is_none_or(|from| dt.date() >= from))
+        .filter(|(dt, _)| baseline.to.is_none_or(|to| dt.date() <= to))
+        .collect();
+    let (Some(first), Some(last)) = (series.first(), series.last()) else {
+        return match baseline.base_date {
+            Some(base_date) => Err(BaselineError::NoData { base_date }),
+            None => Ok(series),
+        }
+    };
+
+    let base = match baseline.base_date {
+        None => first.1,
+        Some(base_date) if base_date < first.0.date() || base_date > last.0.date() => {
+            return Err(BaselineError::OutOfRange {
+                group: group.clone(),
+                base_date,
+                first: first.0.date(),
+                last: last.0.date(),
+            })
+        }
+        Some(base_date) => series.iter().take_while(|(dt, _)| dt.date() <= base_date).last().unwrap().1,
+    };
+    let scale = baseline.base_value / base;
+    Ok(series.into_iter().map(|(dt, value)| (dt, value * scale)).collect())
+}
+
+
+#[derive(Serialize)]
+pub struct InflationPoint {
+    pub date: NaiveDateTime,
+    // Cumulative index, reading `base_value` on the base date.
+    pub index: f64,
+    // Change since the previous point, e.g. 0.01 for +1%.
+    pub change: f64,
+    // Annual rate equivalent to the change between the base date and this
+    // point; only present when asked for with `output=annualised`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annualised: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct InflationSeries {
+    pub group: Option<String>,
+    pub points: Vec<InflationPoint>,
+}
+
+fn to_points(series: &[(NaiveDateTime, f64)], baseline: &Baseline, output: Output) -> Vec<InflationPoint> {
+    let base_date = baseline.base_date.or(series.first().map(|(dt, _)| dt.date()));
+    let mut previous: Option<f64> = None;
+    series
+        .iter()
+        .map(|&(date, index)| {
+            let change = previous.map_or(0.0, |previous| index / previous - 1.0);
+            previous = Some(index);
+            let annualised = (output == Output::Annualised).then(|| {
+                let days = base_date.map_or(0, |base_date| (date.date() - base_date).num_days());
+                if days == 0 {
+                    0.0
+                } else {
+                    (index / baseline.base_value).powf(365.25 / days as f64) - 1.0
+                }
+            });
+            InflationPoint { date, index, change, annualised }
+        })
+        .collect()
+}
+
+
+pub async fn list_baskets(pool: &PgPool) -> Result<Vec<IndexBasket>, sqlx::Error> {
+    sqlx::query_as("SELECT id, name FROM inflation_basket ORDER BY name, id")
+        .fetch_all(pool).await
+}
+
+
+#[derive(sqlx::FromRow)]
+struct BasketPriceRow {
+    item_id: i64,
+    weight: f64,
+    seller: String,
+    sku: i64,
+    day: NaiveDateTime,
+    price: f64,
+}
+
+// One item's prices across the two days of a link, averaged over its
+// listings.
+struct ItemLink {
+    weight: f64,
+    relative: f64,
+    previous_price: f64,
+    price: f64,
+}
+
+fn weighted_mean(items: &[ItemLink]) -> f64 {
+    let total_weight: f64 = items.iter().map(|item| item.weight).sum();
+    items.iter().map(|item| item.weight * item.relative).sum::<f64>() / total_weight
+}
+
+// Combines the item relatives of one link. `items` must not be empty.
+fn link_relative(items: &mut [ItemLink], methodology: &Methodology) -> f64 {
+    items.sort_by(|a, b| a.relative.total_cmp(&b.relative));
+    match methodology.method {
+        Method::Mean => weighted_mean(items),
+        Method::Median => {
+            let half: f64 = items.iter().map(|item| item.weight).sum::<f64>() / 2.0;
+            let mut cumulative = 0.0;
+            items
+                .iter()
+                .find(|item| {
+                    cumulative += item.weight;
+                    cumulative >= half
+                })
+                .map_or(1.0, |item| item.relative)
+        }
+        Method::TrimmedMean => {
+            let cut = (items.len() as f64 * methodology.trim).floor() as usize;
+            weighted_mean(&items[cut..items.len() - cut])
+        }
+        Method::Jevons => {
+            let total_weight: f64 = items.iter().map(|item| item.weight).sum();
+            (items.iter().map(|item| item.weight * item.relative.ln()).sum::<f64>() / total_weight).exp()
+        }
+        Method::Tornqvist => {
+            let previous_spend: f64 = items.iter().map(|item| item.weight * item.previous_price).sum();
+            let spend: f64 = items.iter().map(|item| item.weight * item.price).sum();
+            items
+                .iter()
+                .map(|item| {
+                    let share = (item.weight * item.previous_price / previous_spend + item.weight * item.price / spend) / 2.0;
+                    share * item.relative.ln()
+                })
+                .sum::<f64>()
+                .exp()
+        }
+    }
+}
+
+// A chain-linked index over a weighted basket. Each link compares every
+// listing's latest price on one scrape day with its latest price before
+// it; an item's relative is the mean over its listings, and the link
+// combines item relatives according to `methodology`. Only items priced on
+// both days take part, so items that appear or vanish don't cause jumps.
+pub async fn calc_basket_index(basket_id: i64, methodology: &Methodology, pool: &PgPool) -> Result<Vec<(NaiveDateTime, f64)>, sqlx::Error> {
+    let rows: Vec<BasketPriceRow> = sqlx::query_as(
+        "WITH listings AS (
+            SELECT item.id AS item_id, item.weight, product.seller, product.sku, product.scraped, product.price
+            FROM inflation_basket_item item
+            JOIN product ON product.gtin = item.gtin
+            WHERE item.basket_id = $1
+            UNION
+            SELECT item.id, item.weight, product.seller, product.sku, product.scraped, product.price
+            FROM inflation_basket_item item
+            JOIN product ON product.seller = item.seller AND product.sku = item.sku
+            WHERE item.basket_id = $1 AND item.gtin IS NULL
+        )
+        SELECT DISTINCT ON (item_id, seller, sku, DATE_TRUNC('day', scraped))
+            item_id, weight, seller, sku, DATE_TRUNC('day', scraped) AS day, price
+        FROM listings
+        WHERE price > 0
+        ORDER BY DATE_TRUNC('day', scraped), item_id, seller, sku, scraped DESC"
+    )
+    .bind(basket_id)
+    .fetch_all(pool).await?;
+
+    let mut latest: HashMap<(i64, String, i64), f64> = HashMap::new();
+    let mut series: Vec<(NaiveDateTime, f64)> = Vec::new();
+    let mut index = 1.0;
+    let mut rows = rows.into_iter().peekable();
+    while let Some(first) = rows.peek() {
+        let day = first.day;
+        // item_id -> (weight, sums of listing relative, previous price and
+        // price, listings counted)
+        let mut sums: BTreeMap<i64, (f64, f64, f64, f64, f64)> = BTreeMap::new();
+        while let Some(row) = rows.next_if(|row| row.day == day) {
+            let key = (row.item_id, row.seller, row.sku);
+            let Some(previous) = latest.insert(key, row.price) else {
+                continue
+            };
+            let relative = row.price / previous;
+            if methodology.is_outlier(relative) {
+                continue
+            }
+            let entry = sums.entry(row.item_id).or_insert((row.weight, 0.0, 0.0, 0.0, 0.0));
+            entry.1 += relative;
+            entry.2 += previous;
+            entry.3 += row.price;
+            entry.4 += 1.0;
+        }
+        let mut items: Vec<ItemLink> = sums
+            .into_values()
+            .map(|(weight, relative, previous_price, price, count)| ItemLink {
+                weight,
+                relative: relative / count,
+                previous_price: previous_price / count,
+                price: price / count,
+            })
+            .collect();
+        if !items.is_empty() {
+            index *= link_relative(&mut items, methodology);
+        }
+        series.push((day, index));
+    }
+    Ok(series)
+}
+
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum IndexItemKey {
+    Gtin { gtin: i64 },
+    Listing { seller: String, sku: i64 },
+}
+
+#[derive(Deserialize)]
+pub struct IndexItem {
+    #[serde(flatten)]
+    key: IndexItemKey,
+    weight: f64,
+}
+
+#[derive(Deserialize)]
+pub struct NewBasket {
+    name: String,
+    items: Vec<IndexItem>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedBasket {
+    id: i64,
+}
+
+pub async fn create_basket(State(pool): State<PgPool>, basket: Result<Json<NewBasket>, JsonRejection>) -> Response {
+    let Json(basket) = match basket {
+        Ok(basket) => basket,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    if basket.items.is_empty() {
+        return bad_request("A basket needs at least one item".to_string())
+    }
+    if basket.items.iter().any(|item| !(item.weight > 0.0 && item.weight.is_finite())) {
+        return bad_request("Item weights must be positive".to_string())
+    }
+
+    let result: Result<i64, sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+        let id: i64 = sqlx::query_scalar("INSERT INTO inflation_basket (name) VALUES ($1) RETURNING id")
+            .bind(&basket.name)
+            .fetch_one(&mut *tx).await?;
+        for item in &basket.items {
+            let (gtin, seller, sku) = match &item.key {
+                IndexItemKey::Gtin { gtin } => (Some(*gtin), None, None),
+                IndexItemKey::Listing { seller, sku } => (None, Some(seller), Some(*sku)),
+            };
+            sqlx::query("INSERT INTO inflation_basket_item (basket_id, gtin, seller, sku, weight) VALUES ($1, $2, $3, $4, $5)")
+                .bind(id)
+                .bind(gtin)
+                .bind(seller)
+                .bind(sku)
+                .bind(item.weight)
+                .execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(id)
+    }.await;
+
+    match result {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(id) => (StatusCode::CREATED, Json(CreatedBasket { id })).into_response(),
+    }
+}
+
+
+#[derive(Deserialize)]
+pub struct InflationParams {
+    basket_id: Option<i64>,
+    q: Option<String>,
+    #[serde(default, rename = "match")]
+    match_mode: MatchMode,
+    group_by: Option<GroupBy>,
+    base_date: Option<NaiveDate>,
+    base_value: Option<f64>,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    #[serde(default)]
+    output: Output,
+    #[serde(default)]
+    method: Method,
+    trim: Option<f64>,
+    outlier_factor: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct InflationResponse {
+    pub series: Vec<InflationSeries>,
+}
+
+pub async fn inflation_api(params: Result<Query<InflationParams>, QueryRejection>, State(pool): State<PgPool>) -> Response {
+    let Query(params) = match params {
+        Ok(params) => params,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    let baseline = Baseline {
+        base_date: params.base_date,
+        base_value: params.base_value.unwrap_or(1.0),
+        from: params.from,
+        to: params.to,
+    };
+    if !(baseline.base_value > 0.0 && baseline.base_value.is_finite()) {
+        return bad_request("base_value must be positive".to_string())
+    }
+    if let (Some(from), Some(to)) = (baseline.from, baseline.to) {
+        if from > to {
+            return bad_request("from must not be after to".to_string())
+        }
+    }
+    let methodology = Methodology {
+        method: params.method,
+        trim: params.trim.unwrap_or(Methodology::default().trim),
+        outlier_factor: params.outlier_factor,
+    };
+    if !(0.0..0.5).contains(&methodology.trim) {
+        return bad_request("trim must be at least 0 and below 0.5".to_string())
+    }
+    if methodology.outlier_factor.is_some_and(|factor| !(factor > 1.0 && factor.is_finite())) {
+        return bad_request("outlier_factor must be greater than 1".to_string())
+    }
+    if methodology.method == Method::Tornqvist && params.basket_id.is_none() {
+        return bad_request("tornqvist needs basket weights, so it requires basket_id".to_string())
+    }
+    let filter = NameFilter { text: params.q.unwrap_or_default(), mode: params.match_mode };
+    if let Err(err) = filter.validate() {
+        return bad_request(err.to_string())
+    }
+
+    let result = match params.basket_id {
+        Some(basket_id) => {
+            let exists: Result<bool, sqlx::Error> = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM inflation_basket WHERE id = $1)")
+                .bind(basket_id)
+                .fetch_one(&pool).await;
+            match exists {
+                Err(err) => return AppError::from(err).into_response(),
+                Ok(false) => return AppError::NotFound.into_response(),
+                Ok(true) => {}
+            }
+            if params.group_by.is_some() {
+                return bad_request("group_by can't be combined with basket_id".to_string())
+            }
+            calc_basket_index(basket_id, &methodology, &pool).await.map(|series| vec![(None, series)])
+        }
+        None => calc_inflation_rate2(pool, &filter, params.group_by, &methodology).await,
+    };
+    let groups = match result {
+        Err(err) => {
+            return match FilterError::from_db(&err) {
+                Some(err) => bad_request(err.to_string()),
+                None => AppError::from(err).into_response(),
+            }
+        }
+        Ok(groups) => groups,
+    };
+
+    let mut series = Vec::new();
+    for (group, data) in groups {
+        match apply_baseline(&group, data, &baseline) {
+            Err(err) => return bad_request(err.to_string()),
+            Ok(data) => series.push(InflationSeries {
+                points: to_points(&data, &baseline, params.output),
+                group,
+            }),
+        }
+    }
+    Json(InflationResponse { series }).into_response()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(weight: f64, previous_price: f64, price: f64) -> ItemLink {
+        ItemLink { weight, relative: price / previous_price, previous_price, price }
+    }
+
+    fn relatives(relatives: &[f64]) -> Vec<ItemLink> {
+        relatives.iter().map(|&relative| item(1.0, 1.0, relative)).collect()
+    }
+
+    fn methodology(method: Method) -> Methodology {
+        Methodology { method, ..Methodology::default() }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-12, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn mean_is_weighted() {
+        let mut items = vec![item(1.0, 1.0, 1.0), item(1.0, 1.0, 1.1), item(2.0, 1.0, 1.2)];
+        assert_close(link_relative(&mut items, &methodology(Method::Mean)), (1.0 + 1.1 + 2.0 * 1.2) / 4.0);
+    }
+
+    #[test]
+    fn median_is_the_weighted_middle() {
+        let median = methodology(Method::Median);
+        assert_close(link_relative(&mut relatives(&[1.5, 0.9, 1.0]), &median), 1.0);
+        // With an even count it's the lower of the two middle relatives.
+        assert_close(link_relative(&mut relatives(&[1.4, 1.1, 1.3, 1.2]), &median), 1.2);
+        let mut items = vec![item(1.0, 1.0, 0.9), item(1.0, 1.0, 1.0), item(3.0, 1.0, 1.5)];
+        assert_close(link_relative(&mut items, &median), 1.5);
+    }
+
+    #[test]
+    fn trimmed_mean_cuts_from_both_ends() {
+        let trimmed = Methodology { method: Method::TrimmedMean, trim: 0.1, outlier_factor: None };
+        let mut ten = relatives(&[1.0, 1.0, 2.0, 1.0, 1.0, 1.0, 0.5, 1.0, 1.0, 1.0]);
+        assert_close(link_relative(&mut ten, &trimmed), 1.0);
+    }
+
+    #[test]
+    fn trimmed_mean_keeps_everything_when_the_cut_rounds_down() {
+        let trimmed = Methodology { method: Method::TrimmedMean, trim: 0.1, outlier_factor: None };
+        // 9 * 0.1 rounds down to nothing trimmed.
+        let mut nine = relatives(&[1.0, 1.0, 1.0, 1.0, 2.0, 1.0, 1.0, 1.0, 1.0]);
+        assert_close(link_relative(&mut nine, &trimmed), 10.0 / 9.0);
+        // 19 * 0.1 trims one from each end, not two.
+        let mut nineteen = relatives(&[1.0; 17]);
+        nineteen.extend(relatives(&[0.5, 3.0]));
+        assert_close(link_relative(&mut nineteen, &trimmed), 1.0);
+        // At 20 it trims two, so both high relatives go.
+        let mut twenty = nineteen;
+        twenty.extend(relatives(&[4.0]));
+        assert_close(link_relative(&mut twenty, &trimmed), 1.0);
+    }
+
+    #[test]
+    fn jevons_is_the_weighted_geometric_mean() {
+        let jevons = methodology(Method::Jevons);
+        assert_close(link_relative(&mut relatives(&[2.0, 0.5]), &jevons), 1.0);
+        let mut items = vec![item(3.0, 1.0, 2.0), item(1.0, 1.0, 0.5)];
+        assert_close(link_relative(&mut items, &jevons), 2.0_f64.sqrt());
+    }
+
+    #[test]
+    fn tornqvist_weights_by_average_expenditure_share() {
+        // Spend goes from 1 + 1 to 2 + 1, so the first item's share is the
+        // average of 1/2 and 2/3.
+        let mut items = vec![item(1.0, 1.0, 2.0), item(1.0, 1.0, 1.0)];
+        assert_close(link_relative(&mut items, &methodology(Method::Tornqvist)), 2.0_f64.powf(7.0 / 12.0));
+        // Doubling every price doubles the index whatever the shares.
+        let mut items = vec![item(2.0, 1.0, 2.0), item(1.0, 3.0, 6.0)];
+        assert_close(link_relative(&mut items, &methodology(Method::Tornqvist)), 2.0);
+    }
+
+    #[test]
+    fn mispriced_scrapes_are_outliers() {
+        let methodology = Methodology { outlier_factor: Some(3.0), ..Methodology::default() };
+        assert!(methodology.is_outlier(5.00 / 0.01));
+        assert!(methodology.is_outlier(0.01 / 5.00));
+        assert!(!methodology.is_outlier(3.0));
+        assert!(!methodology.is_outlier(1.0 / 3.0));
+        assert!(!methodology.is_outlier(1.1));
+        assert!(!Methodology::default().is_outlier(5.00 / 0.01));
+    }
+
+    #[test]
+    fn aggregate_sql_matches_the_method() {
+        assert_eq!(methodology(Method::Mean).aggregate_sql(), "1 + AVG(rate)");
+        assert_eq!(methodology(Method::TrimmedMean).aggregate_sql(), "1 + AVG(rate)");
+        assert_eq!(methodology(Method::Median).aggregate_sql(), "1 + PERCENTILE_CONT(0.5) WITHIN GROUP (ORDER BY rate)");
+        assert_eq!(methodology(Method::Jevons).aggregate_sql(), "EXP(AVG(log_rate))");
+        // Only the trimmed mean trims in SQL.
+        assert_eq!(methodology(Method::TrimmedMean).sql_trim(), 0.1);
+        assert_eq!(methodology(Method::Mean).sql_trim(), 0.0);
+        assert_eq!(methodology(Method::Median).sql_trim(), 0.0);
+    }
+
+    fn day(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    // Weekly points rising 10% a week.
+    fn weekly() -> Vec<(NaiveDateTime, f64)> {
+        vec![
+            (day(2024, 1, 1).and_hms_opt(0, 0, 0).unwrap(), 1.0),
+            (day(2024, 1, 8).and_hms_opt(0, 0, 0).unwrap(), 1.1),
+            (day(2024, 1, 15).and_hms_opt(0, 0, 0).unwrap(), 1.21),
+        ]
+    }
+
+    fn values(series: &[(NaiveDateTime, f64)]) -> Vec<f64> {
+        series.iter().map(|&(_, value)| value).collect()
+    }
+
+    fn assert_all_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?} != {expected:?}");
+        for (&actual, &expected) in actual.iter().zip(expected) {
+            assert_close(actual, expected);
+        }
+    }
+
+    #[test]
+    fn baseline_defaults_to_the_first_point_in_the_window() {
+        let series = apply_baseline(&None, weekly(), &Baseline::default()).unwrap();
+        assert_all_close(&values(&series), &[1.0, 1.1, 1.21]);
+
+        let from_second = Baseline { from: Some(day(2024, 1, 8)), ..Baseline::default() };
+        let series = apply_baseline(&None, weekly(), &from_second).unwrap();
+        assert_eq!(series[0].0.date(), day(2024, 1, 8));
+        assert_all_close(&values(&series), &[1.0, 1.1]);
+    }
+
+    #[test]
+    fn baseline_rescales_to_the_base_value_on_the_base_date() {
+        let baseline = Baseline { base_date: Some(day(2024, 1, 8)), base_value: 100.0, ..Baseline::default() };
+        let series = apply_baseline(&None, weekly(), &baseline).unwrap();
+        assert_all_close(&values(&series), &[100.0 / 1.1, 100.0, 110.0]);
+
+        // Between two points, the earlier one is the base.
+        let between = Baseline { base_date: Some(day(2024, 1, 14)), ..baseline };
+        let series = apply_baseline(&None, weekly(), &between).unwrap();
+        assert_all_close(&values(&series), &[100.0 / 1.1, 100.0, 110.0]);
+    }
+
+    #[test]
+    fn baseline_windows_the_series() {
+        let baseline = Baseline { from: Some(day(2024, 1, 2)), to: Some(day(2024, 1, 8)), ..Baseline::default() };
+        let series = apply_baseline(&None, weekly(), &baseline).unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].0.date(), day(2024, 1, 8));
+        assert_close(series[0].1, 1.0);
+    }
+
+    #[test]
+    fn base_dates_outside_the_data_are_rejected() {
+        let group = Some("tesco".to_string());
+        let before = Baseline { base_date: Some(day(2023, 12, 31)), ..Baseline::default() };
+        let err = apply_baseline(&group, weekly(), &before).unwrap_err();
+        assert!(matches!(err, BaselineError::OutOfRange { .. }));
+        assert_eq!(err.to_string(), "base_date 2023-12-31 is outside the available data for tesco (2024-01-01 to 2024-01-15)");
+
+        // The range is the one left after windowing.
+        let after = Baseline { base_date: Some(day(2024, 1, 15)), to: Some(day(2024, 1, 8)), ..Baseline::default() };
+        let err = apply_baseline(&None, weekly(), &after).unwrap_err();
+        assert_eq!(err.to_string(), "base_date 2024-01-15 is outside the available data (2024-01-01 to 2024-01-08)");
+    }
+
+    #[test]
+    fn an_empty_window_only_fails_with_a_base_date() {
+        let empty = Baseline { from: Some(day(2025, 1, 1)), ..Baseline::default() };
+        assert!(apply_baseline(&None, weekly(), &empty).unwrap().is_empty());
+
+        let with_base_date = Baseline { base_date: Some(day(2025, 1, 1)), ..empty };
+        let err = apply_baseline(&None, weekly(), &with_base_date).unwrap_err();
+        assert!(matches!(err, BaselineError::NoData { .. }));
+        assert_eq!(err.to_string(), "base_date 2025-01-01 can't be used: there is no data in the requested window");
+    }
+
+    #[test]
+    fn points_carry_the_change_since_the_previous_one() {
+        let points = to_points(&weekly(), &Baseline::default(), Output::Cumulative);
+        assert_all_close(&points.iter().map(|point| point.index).collect::<Vec<_>>(), &[1.0, 1.1, 1.21]);
+        assert_all_close(&points.iter().map(|point| point.change).collect::<Vec<_>>(), &[0.0, 0.1, 0.1]);
+        assert!(points.iter().all(|point| point.annualised.is_none()));
+    }
+
+    #[test]
+    fn annualised_rates_run_from_the_base_date() {
+        let series = vec![
+            (day(2024, 1, 1).and_hms_opt(0, 0, 0).unwrap(), 100.0),
+            (day(2024, 7, 1).and_hms_opt(0, 0, 0).unwrap(), 105.0),
+            (day(2025, 1, 1).and_hms_opt(0, 0, 0).unwrap(), 110.0),
+        ];
+        let baseline = Baseline { base_value: 100.0, ..Baseline::default() };
+        let points = to_points(&series, &baseline, Output::Annualised);
+        let annualised: Vec<f64> = points.iter().map(|point| point.annualised.unwrap()).collect();
+        // 2024 was a leap year, so the last point is 366 days on.
+        assert_all_close(&annualised, &[0.0, 1.05_f64.powf(365.25 / 182.0) - 1.0, 1.1_f64.powf(365.25 / 366.0) - 1.0]);
+    }
+
+    fn filter(mode: MatchMode, text: &str) -> NameFilter {
+        NameFilter { text: text.to_string(), mode }
+    }
+
+    #[test]
+    fn substring_filters_match_wildcards_literally() {
+        assert_eq!(filter(MatchMode::Substring, "").bind_value(), "%%");
+        assert_eq!(filter(MatchMode::Substring, "orange").bind_value(), "%orange%");
+        assert_eq!(filter(MatchMode::Substring, r"50%_off\").bind_value(), r"%50\%\_off\\%");
+        assert_eq!(filter(MatchMode::FullText, r"50%_off\").bind_value(), r"50%_off\");
+        assert_eq!(filter(MatchMode::Regex, "^50%_off$").bind_value(), "^50%_off$");
+    }
+
+    #[test]
+    fn only_regex_filters_are_validated() {
+        let long = "(".repeat(MAX_REGEX_LEN + 1);
+        assert!(filter(MatchMode::Substring, &long).validate().is_ok());
+        assert!(filter(MatchMode::FullText, &long).validate().is_ok());
+        assert!(filter(MatchMode::Regex, "^orange.*(juice|squash)$").validate().is_ok());
+    }
+
+    #[test]
+    fn long_regexes_are_rejected() {
+        assert!(filter(MatchMode::Regex, &"a".repeat(MAX_REGEX_LEN)).validate().is_ok());
+        let err = filter(MatchMode::Regex, &"a".repeat(MAX_REGEX_LEN + 1)).validate().unwrap_err();
+        assert!(matches!(err, FilterError::TooLong));
+        assert_eq!(err.to_string(), "regex must be at most 200 characters");
+    }
+
+    #[test]
+    fn invalid_and_backtracking_regexes_are_rejected() {
+        for pattern in ["(", "[a-", r"(a)\1", "juice(?=s)", "(?!smooth)juice", "(?<=orange )juice"] {
+            let err = filter(MatchMode::Regex, pattern).validate().unwrap_err();
+            assert!(matches!(err, FilterError::InvalidRegex(_)), "{pattern}");
+            assert!(err.to_string().starts_with("invalid regex: "), "{pattern}: {err}");
+        }
+    }
+
+    #[test]
+    fn only_database_errors_are_filter_errors() {
+        assert!(FilterError::from_db(&sqlx::Error::RowNotFound).is_none());
+        assert!(FilterError::from_db(&sqlx::Error::PoolTimedOut).is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL pointing at a database"]
+    async fn regex_failures_in_postgres_are_filter_errors() {
+        let pool = PgPool::connect(&std::env::var("DATABASE_URL").expect("DATABASE_URL must be set")).await.unwrap();
+
+        let err = sqlx::query("SELECT 'orange' ~* '('").execute(&pool).await.unwrap_err();
+        let err = FilterError::from_db(&err).unwrap();
+        assert!(matches!(err, FilterError::InvalidRegex(_)));
+        assert!(err.to_string().starts_with("invalid regex: invalid regular expression"), "{err}");
+
+        let mut tx = pool.begin().await.unwrap();
+        sqlx::query("SET LOCAL statement_timeout = 10").execute(&mut *tx).await.unwrap();
+        let err = sqlx::query("SELECT pg_sleep(1)").execute(&mut *tx).await.unwrap_err();
+        let err = FilterError::from_db(&err).unwrap();
+        assert!(matches!(err, FilterError::Timeout));
+        assert_eq!(err.to_string(), "regex took too long to run; try a simpler pattern");
+
+        // Other database errors are left to the caller.
+        let err = sqlx::query("SELECT 1 / 0").execute(&pool).await.unwrap_err();
+        assert!(FilterError::from_db(&err).is_none());
+    }
+}
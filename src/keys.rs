@@ -0,0 +1,366 @@
+use askama::Template;
+use axum::{
+    extract::Path,
+    extract::rejection::JsonRejection,
+    http::StatusCode,
+    response::{IntoResponse, Html, Response},
+    Form,
+    Json,
+    extract::State,
+};
+use axum_login::AuthUser;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::auth::AuthSession;
+use crate::db::ApiKey;
+use crate::error::{bad_request, AppError};
+
+const KEY_PREFIX: &str = "sk_";
+// How much of a key is kept in clear so users can tell their keys apart.
+const DISPLAY_PREFIX_LEN: usize = 11;
+const MAX_LABEL_LEN: usize = 100;
+const API_KEY_COLUMNS: &str = "id, label, prefix, calls_made, credit_cap, created_at, last_used_at, revoked_at";
+
+
+#[derive(Deserialize)]
+pub struct KeyLabel {
+    #[serde(default)]
+    label: String,
+}
+
+#[derive(Deserialize)]
+pub struct KeyCap {
+    credit_cap: Option<i32>,
+}
+
+// Forms send an empty field for "no cap".
+#[derive(Deserialize)]
+pub struct KeyCapForm {
+    #[serde(default)]
+    credit_cap: String,
+}
+
+#[derive(Serialize)]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+#[derive(Template)]
+#[template(path = "api_keys.html")]
+struct ApiKeysTemplate {
+    keys: Vec<ApiKey>,
+    new_key: Option<String>,
+    message: Option<String>,
+}
+
+
+// Legacy keys are UUIDs, which clients may send in any case, so they're
+// hashed in their canonical form.
+pub fn hash_key(key: &str) -> String {
+    let key = match Uuid::try_parse(key) {
+        Ok(uuid) => uuid.hyphenated().to_string(),
+        Err(_) => key.to_string(),
+    };
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn generate_key() -> String {
+    format!("{KEY_PREFIX}{}", Uuid::new_v4().simple())
+}
+
+fn validate_label(label: &str) -> Result<(), String> {
+    if label.chars().count() > MAX_LABEL_LEN {
+        return Err(format!("label must be at most {MAX_LABEL_LEN} characters"))
+    }
+    Ok(())
+}
+
+fn validate_cap(credit_cap: Option<i32>) -> Result<(), String> {
+    if credit_cap.is_some_and(|cap| cap <= 0) {
+        return Err("credit_cap must be positive".to_string())
+    }
+    Ok(())
+}
+
+
+pub async fn list_keys(users_id: i64, pool: &PgPool) -> Result<Vec<ApiKey>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "SELECT {API_KEY_COLUMNS} FROM api_key WHERE users_id = $1 ORDER BY revoked_at IS NOT NULL, id"
+    ))
+    .bind(users_id)
+    .fetch_all(pool).await
+}
+
+pub async fn create_key(users_id: i64, label: &str, pool: &PgPool) -> Result<NewApiKey, sqlx::Error> {
+    let key = generate_key();
+    let api_key: ApiKey = sqlx::query_as(&format!(
+        "INSERT INTO api_key (users_id, key_hash, prefix, label) VALUES ($1, $2, $3, $4)
+        RETURNING {API_KEY_COLUMNS}"
+    ))
+    .bind(users_id)
+    .bind(hash_key(&key))
+    .bind(&key[..DISPLAY_PREFIX_LEN])
+    .bind(label)
+    .fetch_one(pool).await?;
+    Ok(NewApiKey { api_key, key })
+}
+
+pub async fn set_label(users_id: i64, key_id: i64, label: &str, pool: &PgPool) -> Result<Option<ApiKey>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "UPDATE api_key SET label = $3 WHERE id = $1 AND users_id = $2
+        RETURNING {API_KEY_COLUMNS}"
+    ))
+    .bind(key_id)
+    .bind(users_id)
+    .bind(label)
+    .fetch_optional(pool).await
+}
+
+pub async fn set_cap(users_id: i64, key_id: i64, credit_cap: Option<i32>, pool: &PgPool) -> Result<Option<ApiKey>, sqlx::Error> {
+    sqlx::query_as(&format!(
+        "UPDATE api_key SET credit_cap = $3 WHERE id = $1 AND users_id = $2
+        RETURNING {API_KEY_COLUMNS}"
+    ))
+    .bind(key_id)
+    .bind(users_id)
+    .bind(credit_cap)
+    .fetch_optional(pool).await
+}
+
+// Swaps in a new secret for the same key, so its label and usage carry over
+// and the old secret stops working straight away.
+pub async fn rotate_key(users_id: i64, key_id: i64, pool: &PgPool) -> Result<Option<NewApiKey>, sqlx::Error> {
+    let key = generate_key();
+    let api_key: Option<ApiKey> = sqlx::query_as(&format!(
+        "UPDATE api_key SET key_hash = $3, prefix = $4
+        WHERE id = $1 AND users_id = $2 AND revoked_at IS NULL
+        RETURNING {API_KEY_COLUMNS}"
+    ))
+    .bind(key_id)
+    .bind(users_id)
+    .bind(hash_key(&key))
+    .bind(&key[..DISPLAY_PREFIX_LEN])
+    .fetch_optional(pool).await?;
+    Ok(api_key.map(|api_key| NewApiKey { api_key, key }))
+}
+
+// Revoked keys are kept so their usage history stays attributable.
+pub async fn revoke_key(users_id: i64, key_id: i64, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE api_key SET revoked_at = NOW() WHERE id = $1 AND users_id = $2 AND revoked_at IS NULL")
+        .bind(key_id)
+        .bind(users_id)
+        .execute(pool).await?;
+    Ok(result.rows_affected() > 0)
+}
+
+
+pub async fn get_keys(auth_session: AuthSession, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    match list_keys(user.id(), &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(keys) => Json(keys).into_response(),
+    }
+}
+
+pub async fn post_key(auth_session: AuthSession, State(pool): State<PgPool>, request: Result<Json<KeyLabel>, JsonRejection>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let Json(KeyLabel { label }) = match request {
+        Ok(label) => label,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    if let Err(detail) = validate_label(&label) {
+        return bad_request(detail)
+    }
+    match create_key(user.id(), &label, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(new_key) => (StatusCode::CREATED, Json(new_key)).into_response(),
+    }
+}
+
+pub async fn patch_key(auth_session: AuthSession, Path(key_id): Path<i64>, State(pool): State<PgPool>, request: Result<Json<KeyLabel>, JsonRejection>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let Json(KeyLabel { label }) = match request {
+        Ok(label) => label,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    if let Err(detail) = validate_label(&label) {
+        return bad_request(detail)
+    }
+    match set_label(user.id(), key_id, &label, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(None) => AppError::NotFound.into_response(),
+        Ok(Some(api_key)) => Json(api_key).into_response(),
+    }
+}
+
+// `null` removes the cap.
+pub async fn put_key_cap(auth_session: AuthSession, Path(key_id): Path<i64>, State(pool): State<PgPool>, request: Result<Json<KeyCap>, JsonRejection>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let Json(KeyCap { credit_cap }) = match request {
+        Ok(cap) => cap,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    if let Err(detail) = validate_cap(credit_cap) {
+        return bad_request(detail)
+    }
+    match set_cap(user.id(), key_id, credit_cap, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(None) => AppError::NotFound.into_response(),
+        Ok(Some(api_key)) => Json(api_key).into_response(),
+    }
+}
+
+pub async fn post_rotate_key(auth_session: AuthSession, Path(key_id): Path<i64>, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    match rotate_key(user.id(), key_id, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(None) => AppError::NotFound.into_response(),
+        Ok(Some(new_key)) => Json(new_key).into_response(),
+    }
+}
+
+pub async fn delete_key(auth_session: AuthSession, Path(key_id): Path<i64>, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    match revoke_key(user.id(), key_id, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(false) => AppError::NotFound.into_response(),
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+
+// The account page is plain HTML forms: every action re-renders the page,
+// showing a new key once if the action made one.
+async fn keys_page(users_id: i64, new_key: Option<String>, message: Option<String>, pool: &PgPool) -> Response {
+    match list_keys(users_id, pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(keys) => Html(ApiKeysTemplate { keys, new_key, message }.render().unwrap()).into_response(),
+    }
+}
+
+pub async fn get_keys_page(auth_session: AuthSession, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    keys_page(user.id(), None, None, &pool).await
+}
+
+pub async fn post_keys_page(auth_session: AuthSession, State(pool): State<PgPool>, Form(KeyLabel { label }): Form<KeyLabel>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    if let Err(message) = validate_label(&label) {
+        return keys_page(user.id(), None, Some(message), &pool).await
+    }
+    match create_key(user.id(), &label, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(new_key) => keys_page(user.id(), Some(new_key.key), None, &pool).await,
+    }
+}
+
+pub async fn post_label_page(auth_session: AuthSession, Path(key_id): Path<i64>, State(pool): State<PgPool>, Form(KeyLabel { label }): Form<KeyLabel>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    if let Err(message) = validate_label(&label) {
+        return keys_page(user.id(), None, Some(message), &pool).await
+    }
+    match set_label(user.id(), key_id, &label, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(_) => keys_page(user.id(), None, None, &pool).await,
+    }
+}
+
+pub async fn post_cap_page(auth_session: AuthSession, Path(key_id): Path<i64>, State(pool): State<PgPool>, Form(form): Form<KeyCapForm>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    let credit_cap = match form.credit_cap.trim() {
+        "" => None,
+        cap => match cap.parse::<i32>() {
+            Ok(cap) => Some(cap),
+            Err(_) => return keys_page(user.id(), None, Some("credit_cap must be a whole number".to_string()), &pool).await,
+        },
+    };
+    if let Err(message) = validate_cap(credit_cap) {
+        return keys_page(user.id(), None, Some(message), &pool).await
+    }
+    match set_cap(user.id(), key_id, credit_cap, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(_) => keys_page(user.id(), None, None, &pool).await,
+    }
+}
+
+pub async fn post_rotate_page(auth_session: AuthSession, Path(key_id): Path<i64>, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    match rotate_key(user.id(), key_id, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(new_key) => keys_page(user.id(), new_key.map(|new_key| new_key.key), None, &pool).await,
+    }
+}
+
+pub async fn post_revoke_page(auth_session: AuthSession, Path(key_id): Path<i64>, State(pool): State<PgPool>) -> Response {
+    let Some(user) = auth_session.user else {
+        return AppError::NotLoggedIn.into_response()
+    };
+    match revoke_key(user.id(), key_id, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(_) => keys_page(user.id(), None, None, &pool).await,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // encode(sha256(convert_to(key::text, 'UTF8')), 'hex') for this key, as
+    // migration 0008 computed it for existing rows.
+    const LEGACY_KEY: &str = "6f1c2a8e-8b7d-4c51-9d0e-1a2b3c4d5e6f";
+    const LEGACY_KEY_HASH: &str = "b5d96553e10140c5e768ce566622299362b88e3075b5a0632d1a10a58977adca";
+
+    #[test]
+    fn legacy_keys_hash_as_the_migration_did() {
+        assert_eq!(hash_key(LEGACY_KEY), LEGACY_KEY_HASH);
+        assert_eq!(hash_key("6F1C2A8E-8B7D-4C51-9D0E-1A2B3C4D5E6F"), LEGACY_KEY_HASH);
+        assert_eq!(hash_key("6f1c2a8e8b7d4c519d0e1a2b3c4d5e6f"), LEGACY_KEY_HASH);
+        assert_eq!(hash_key("{6f1c2a8e-8b7d-4c51-9d0e-1a2b3c4d5e6f}"), LEGACY_KEY_HASH);
+    }
+
+    #[test]
+    fn new_keys_are_hashed_verbatim() {
+        let key = "sk_0123456789abcdef0123456789abcdef";
+        assert_eq!(hash_key(key), "9baa890b171009c370f3c7c9e9cf5cee069a16d69871a7aaf98317ec37349908");
+        assert_ne!(hash_key(&key.to_uppercase()), hash_key(key));
+    }
+
+    #[test]
+    fn generated_keys_start_with_their_display_prefix() {
+        let key = generate_key();
+        assert_eq!(key.len(), KEY_PREFIX.len() + 32);
+        let prefix = &key[..DISPLAY_PREFIX_LEN];
+        assert!(prefix.starts_with(KEY_PREFIX));
+        assert!(prefix[KEY_PREFIX.len()..].chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+        assert_eq!(hash_key(&key), hex::encode(Sha256::digest(key.as_bytes())));
+        assert_ne!(generate_key(), key);
+    }
+}
@@ -1,34 +1,49 @@
 use std::{
-    time::Instant,
-    collections::HashMap
+    collections::HashMap,
+    sync::Arc,
 };
 use serde::Serialize;
-use chrono::{NaiveDateTime, NaiveDate};
 use dotenv::dotenv;
 use axum::{
+    extract::MatchedPath,
     extract::Path,
+    extract::rejection::PathRejection,
     extract::Query,
     extract::Request,
+    extract::State,
     http::StatusCode,
     http,
     response::{IntoResponse, Html, Response},
+    routing::delete,
     routing::get,
+    routing::patch,
     routing::post,
+    routing::put,
     Json,
     Router,
-    Extension,
     middleware::{self, Next},
 };
-use sqlx::{
-    Error,
-    PgPool,
-    Pool,
-    Postgres,
-};
+use sqlx::PgPool;
 use askama::Template;
 
 mod db;
+mod alerts;
+mod config;
 mod auth;
+mod basket;
+mod digest;
+mod error;
+mod history;
+mod inflation;
+mod keys;
+mod matching;
+mod plans;
+mod ratelimit;
+mod search;
+mod sessions;
+mod state;
+mod units;
+mod usage;
 use auth::{
     get_login,
     post_login,
@@ -37,14 +52,25 @@ use auth::{
     post_register,
     Backend,
 };
+use config::Config;
 use db::{
-    db_conn,
     Product,
+    SellerListing,
     DebugInfo,
-    ApiKey,
-    CreditsPeriod
 };
-use uuid::Uuid;
+use error::{bad_request, AppError};
+use ratelimit::RateLimiter;
+use state::AppState;
+use usage::Charge;
+use search::{
+    search_for_product,
+    Cursor,
+    search_fingerprint,
+    SearchFilters,
+    SortField,
+    SortOrder,
+    DEFAULT_LIMIT,
+};
 
 #[derive(Serialize)]
 struct JStatus {
@@ -54,34 +80,81 @@ struct JStatus {
 use axum::{error_handling::HandleErrorLayer, BoxError};
 use axum_login::{
     login_required,
-    tower_sessions::{Expiry, MemoryStore, SessionManagerLayer},
-    AuthManagerLayerBuilder, AuthnBackend,
+    tower_sessions::{Expiry, SessionManagerLayer},
+    AuthManagerLayerBuilder,
 };
 use time::Duration;
 use tower::ServiceBuilder;
+use tracing_subscriber::EnvFilter;
 
 
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    let pool = db_conn().await;
-    tracing_subscriber::fmt::init();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Configuration error: {err}");
+            std::process::exit(1);
+        }
+    };
+    tracing_subscriber::fmt().with_env_filter(EnvFilter::new(&config.log_level)).init();
+    let pool = match db::connect(&config).await {
+        Ok(pool) => pool,
+        Err(err) => {
+            tracing::error!("couldn't connect to the database: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = sqlx::migrate!().run(&pool).await {
+        tracing::error!("couldn't run database migrations: {}", err);
+        std::process::exit(1);
+    }
 
+    tokio::spawn(matching::run_periodically(pool.clone(), tokio::time::Duration::from_secs(10 * 60)));
+    tokio::spawn(units::run_periodically(pool.clone(), tokio::time::Duration::from_secs(60)));
+    tokio::spawn(inflation::run_periodically(pool.clone(), tokio::time::Duration::from_secs(60)));
+    tokio::spawn(alerts::run_periodically(pool.clone(), config.allow_private_webhooks, tokio::time::Duration::from_secs(60)));
+    match digest::SmtpMailer::from_env() {
+        None => tracing::info!("SMTP_URL or DIGEST_FROM not set, email digests are disabled"),
+        Some(Err(err)) => tracing::error!("email digests are disabled: {}", err),
+        Some(Ok(mailer)) => {
+            tokio::spawn(digest::run_periodically(pool.clone(), mailer, tokio::time::Duration::from_secs(60 * 60)));
+        }
+    }
+    let bind_address = config.bind_address;
+    let state = AppState { pool: pool.clone(), rate_limiter: RateLimiter::new(), config: Arc::new(config) };
+    tokio::spawn(sessions::run_periodically(pool.clone(), tokio::time::Duration::from_secs(60 * 60)));
+    tokio::spawn(ratelimit::prune_periodically(state.rate_limiter.clone(), tokio::time::Duration::from_secs(5 * 60)));
+
+    let listener = match tokio::net::TcpListener::bind(bind_address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            tracing::error!("couldn't listen on {}: {}", bind_address, err);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("listening on {}", bind_address);
+    axum::serve(listener, app(state).into_make_service()).await.unwrap();
+}
+
+// Every route, sharing the one pool in `state`.
+fn app(state: AppState) -> Router {
         // Session layer.
     //
     // This uses `tower-sessions` to establish a layer that will provide the session
     // as a request extension.
-    let session_store = MemoryStore::default();
+    let session_store = sessions::PgSessionStore::new(state.pool.clone());
     let session_layer = SessionManagerLayer::new(session_store)
-        .with_secure(false)
-        .with_expiry(Expiry::OnInactivity(Duration::days(1)));
+        .with_secure(state.config.secure_cookies)
+        .with_expiry(Expiry::OnInactivity(Duration::hours(state.config.session_expiry_hours)));
 
     // Auth service.
     //
     // This combines the session layer with our backend to establish the auth
     // service which will provide the auth session as a request extension.
-    let backend = Backend::new(pool.clone());
+    let backend = Backend::new(state.pool.clone());
     let auth_service = ServiceBuilder::new()
         .layer(HandleErrorLayer::new(|_: BoxError| async {
             StatusCode::BAD_REQUEST
@@ -91,14 +164,38 @@ async fn main() {
 
     let api_routes = Router::new()
         .route("/products/:product_id", get(product))
-        .route("/products/search", get(search))
-        .route_layer(middleware::from_fn(verify_header_api_key))
-        .route("/ping", get(ping));
+        .route("/products/:product_id/history", get(history::history))
+        .route("/sellers/:seller/products/:sku", get(seller_product))
+        .route("/products/search", get(search::search))
+        .route("/compare", get(matching::compare))
+        .route("/basket", post(basket::basket))
+        .route("/inflation", get(inflation::inflation_api))
+        .route("/inflation/baskets", post(inflation::create_basket))
+        .route("/usage", get(usage::usage))
+        .route_layer(middleware::from_fn_with_state(state.clone(), verify_header_api_key))
+        .route_layer(middleware::from_fn_with_state(state.clone(), ratelimit::rate_limit))
+        .route("/ping", get(ping))
+        .route("/plans", get(plans::list_plans));
     let static_routes = Router::new()
         .route("/styles", get(styles))
         .route("/logo", get(logo));
     let authed_routes = Router::new()
         .route("/debug-dashboard", get(debug_dashboard))
+        .route("/alerts/watches", get(alerts::list_watches).post(alerts::create_watch))
+        .route("/alerts/watches/:watch_id", delete(alerts::delete_watch))
+        .route("/alerts/webhook", get(alerts::get_webhook).put(alerts::put_webhook))
+        .route("/alerts/deliveries", get(alerts::list_deliveries))
+        .route("/digest", get(digest::get_subscription).put(digest::put_subscription).delete(digest::delete_subscription))
+        .route("/digest/preview", get(digest::preview))
+        .route("/keys", get(keys::get_keys).post(keys::post_key))
+        .route("/keys/:key_id", patch(keys::patch_key).delete(keys::delete_key))
+        .route("/keys/:key_id/rotate", post(keys::post_rotate_key))
+        .route("/keys/:key_id/cap", put(keys::put_key_cap))
+        .route("/account/keys", get(keys::get_keys_page).post(keys::post_keys_page))
+        .route("/account/keys/:key_id/label", post(keys::post_label_page))
+        .route("/account/keys/:key_id/rotate", post(keys::post_rotate_page))
+        .route("/account/keys/:key_id/cap", post(keys::post_cap_page))
+        .route("/account/keys/:key_id/revoke", post(keys::post_revoke_page))
         .route_layer(login_required!(Backend, login_url = "/login"))
         .route("/login", post(post_login))
         .route("/login", get(get_login))
@@ -106,21 +203,17 @@ async fn main() {
         .route("/register", get(get_register))
         .route("/logout", get(get_logout))
         .layer(auth_service);
-    let app = Router::new()
+    Router::new()
         .nest("/api", api_routes)
         .nest("/static", static_routes)
         .route("/", get(root))
-        .route("/inflation", get(inflation))
-        .route("/inflation-viz", get(inflation_viz))
+        .route("/inflation", get(inflation::inflation))
+        .route("/inflation-viz", get(inflation::inflation_viz))
         .route("/search-pretty-results", get(search_pretty_results))
         .route("/search", get(search_pretty_page))
         .merge(authed_routes)
-        .layer(Extension(pool));
-
-    let addr = "0.0.0.0:3000";
-    tracing::debug!("listening on {}", addr);
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app.into_make_service()).await.unwrap();
+        .layer(middleware::from_fn(error::request_id))
+        .with_state(state)
 }
 
 
@@ -139,15 +232,6 @@ async fn ping() -> (StatusCode, Json<JStatus>) {
     (StatusCode::OK, Json(JStatus { detail: true }))
 }
 
-#[derive(Template)]
-#[template(path="inflation.html")]
-struct InflationTemplate {}
-
-async fn inflation() -> Html<String> {
-    let inflation_template = InflationTemplate {};
-    Html(inflation_template.render().unwrap())
-}
-
 async fn styles() -> impl IntoResponse {
     Response::builder()
         .status(StatusCode::OK)
@@ -166,121 +250,14 @@ async fn logo() -> impl IntoResponse {
 }
 
 
-async fn calc_inflation_rate2(pool: Pool<Postgres>, namefilter: Option<&String>) -> Vec<(NaiveDateTime, f64)> {
-    let now = Instant::now();
-
-    let query = "
-    SELECT DATE_TRUNC('day', to_date), 1 + AVG(increase / years) / 365 FROM
-        (
-        SELECT
-            seller, sku,
-            price,
-            price / LAG(price) OVER (PARTITION BY seller, sku ORDER BY scraped) - 1 AS increase,
-            LAG(scraped) OVER (PARTITION BY seller, sku ORDER BY scraped) from_date,
-            scraped AS to_date,
-            EXTRACT(epoch FROM 
-                    scraped - LAG(scraped) OVER (PARTITION BY seller, sku ORDER BY scraped)
-            ) / (60 * 60 * 24 * 365.25) AS years
-        
-        FROM product
-        WHERE price > 0 AND name ~* $1
-        ORDER BY seller, sku, scraped
-        ) t1
-    WHERE increase IS NOT NULL AND years > 0
-    GROUP BY DATE_TRUNC('day', to_date)
-    ORDER BY DATE_TRUNC('day', to_date)
-    ";
-    let result: Result<Vec<(NaiveDateTime, f64)>, sqlx::Error> = sqlx::query_as(query).bind(namefilter.unwrap_or(&"".to_string())).fetch_all(&pool).await;
-    println!("Query done in: {:.4?}", now.elapsed());
-
-    let random_dt = NaiveDate::from_ymd_opt(2023, 8, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
-
-    let mut inflation_data = result.unwrap();
-    inflation_data.insert(0, (random_dt, 1.0));
-    inflation_data = inflation_data.into_iter().scan((random_dt, 1.0), |state, x| {
-        state.0 = x.0;
-        state.1 = state.1 * x.1;
-        Some(*state)
-    }).collect();
-
-
-    println!("Total: {:.4?}", now.elapsed());
-    inflation_data
-}
-
-
-async fn inflation_viz(Query(params): Query<HashMap<String, String>>, Extension(pool): Extension<PgPool>) -> Html<String> {
-    let namefilter = params.get("q");
-    let is_table = params.get("table").is_some();
-    let inflation_data = calc_inflation_rate2(pool, namefilter).await;
-    let final_table: String = inflation_data
-        .iter()
-        .map(|(dt, val)| format!("<tr><td>{}</td><td>{:.3}</td></tr>", dt.date(), val))
-        .collect::<Vec<String>>()
-        .join("\n");
-    let table_html = format!(r#"<table class="table table-sm">{final_table}</table>"#);
-
-    let (x, y): (Vec<NaiveDateTime>, Vec<f64>) = inflation_data.into_iter().unzip();
-    let x: Vec<String> = x.into_iter().map(|d| d.date().to_string()).collect();
-    let chart_html = format!(r#"
-    <div class="chart-container" style="position: relative; height: 70vh; width: 100vw;">
-        <canvas id="inflation-chart"></canvas>
-    </div>
-
-    <script>
-    var datasets = [{{
-        label: "inflation",
-        data: {y:?},
-        pointHitRadius: 10,
-        pointRadius: 0,
-        borderColor: "black",
-        backgroundColor: "black"
-
-    }}];
-    var labels = {x:?};
-    var chart_type = "line";
-    var data = {{
-        labels: labels,
-        datasets: datasets,    
-    }};
-
-    var config = {{
-        type: chart_type,
-        data: data,
-        options: {{
-            scales: {{
-                y: {{
-                    grace: "20%",
-                }},
-                x: {{
-                    type: 'time',
-                    grid: {{
-                        display: false
-                    }}
-                }}
-            }},
-            animation: {{
-                duration: 0,
-            }},
-            maintainAspectRatio: false
-        }}
-    }};
-    var InflationChart = new Chart(
-        document.getElementById('inflation-chart'),
-        config,
-    );
-    </script>
-    "#);
-
-    let output_html = if is_table {table_html} else {chart_html};
-    Html(format!(r#"<div id="inflation-viz">{output_html}</div>"#))
-}
-
-
-async fn product(Path(product_id): Path<i32>, Extension(pool): Extension<PgPool>) -> impl IntoResponse {
+async fn product(product_id: Result<Path<i64>, PathRejection>, State(pool): State<PgPool>) -> impl IntoResponse {
+    let Path(product_id) = match product_id {
+        Ok(product_id) => product_id,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
 
     let result: Result<Product, sqlx::Error> = sqlx::query_as(
-        "SELECT gtin, name, sku, image, description, rating, review_count, brand, price, url, availability, seller
+        "SELECT gtin, name, sku, image, description, rating, review_count, brand, price, url, availability, seller, price_per_unit, price_unit
         FROM product
         WHERE gtin = $1
         ORDER BY scraped DESC"
@@ -289,55 +266,89 @@ async fn product(Path(product_id): Path<i32>, Extension(pool): Extension<PgPool>
     .fetch_one(&pool).await;
 
     match result {
-        Err(Error::RowNotFound) => {StatusCode::NOT_FOUND.into_response()}
-        Err(value) => {panic!("{}", value)}
-        Ok(product) => {Json(product).into_response()}
+        Err(err) => AppError::from(err).into_response(),
+        Ok(product) => Json(product).into_response(),
     }
 }
 
 
-async fn search_for_product(query: String, sort: &String, pool: PgPool) -> Result<Vec<Product>, sqlx::Error>{
-    let result: Result<Vec<Product>, sqlx::Error> = sqlx::query_as(
-        format!(
-            "SELECT * FROM (
-                SELECT DISTINCT ON (seller, sku) gtin, name, sku, image, description, rating, review_count, brand, price, url, availability, seller
-                FROM product
-                WHERE name ILIKE $1
-                ORDER BY seller, sku, scraped DESC
-            ) t1
-            ORDER BY {sort} ASC
-            LIMIT 10"
-        ).as_str()
-    )
-    .bind(query).bind(sort) 
-    .fetch_all(&pool).await;
-    return result
+#[derive(Serialize)]
+struct ProductWithSellers {
+    product: Product,
+    sellers: Vec<SellerListing>,
 }
 
 
-async fn search(Query(params): Query<HashMap<String, String>>, Extension(pool): Extension<PgPool>) -> impl IntoResponse {
-    let query = format!("%{}%", params.get("query").unwrap());
-    let default_sort = &"name".to_string();
-    let sort = params.get("sort").unwrap_or(default_sort); // SANITIZE THIS!!
-    let result = search_for_product(query, sort, pool).await;
+async fn seller_product(listing: Result<Path<(String, i64)>, PathRejection>, State(pool): State<PgPool>) -> impl IntoResponse {
+    let Path((seller, sku)) = match listing {
+        Ok(listing) => listing,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
 
-    match result {
-        Err(Error::RowNotFound) => {StatusCode::NOT_FOUND.into_response()}
-        Err(value) => {panic!("{}", value)}
-        Ok(rows) => {Json(rows).into_response()}
+    let result: Result<Product, sqlx::Error> = sqlx::query_as(
+        "SELECT gtin, name, sku, image, description, rating, review_count, brand, price, url, availability, seller, price_per_unit, price_unit
+        FROM product
+        WHERE seller = $1 AND sku = $2
+        ORDER BY scraped DESC"
+    )
+    .bind(&seller)
+    .bind(sku)
+    .fetch_one(&pool).await;
+
+    let product = match result {
+        Err(err) => return AppError::from(err).into_response(),
+        Ok(product) => product,
+    };
+
+    // Other sellers' listings are linked either by GTIN or, failing that,
+    // through the canonical product the matcher assigned.
+    let sellers: Result<Vec<SellerListing>, sqlx::Error> = sqlx::query_as(
+        "SELECT DISTINCT ON (seller, sku) seller, sku, price, availability, url
+        FROM product
+        WHERE (seller = $1 AND sku = $2) OR gtin = $3 OR (seller, sku) IN (
+            SELECT seller, sku FROM product_match
+            WHERE canonical_product_id = (
+                SELECT canonical_product_id FROM product_match WHERE seller = $1 AND sku = $2
+            )
+        )
+        ORDER BY seller, sku, scraped DESC"
+    )
+    .bind(&seller)
+    .bind(sku)
+    .bind(product.gtin)
+    .fetch_all(&pool).await;
+
+    match sellers {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(sellers) => Json(ProductWithSellers { product, sellers }).into_response(),
     }
 }
 
 
-async fn search_pretty_results(Query(params): Query<HashMap<String, String>>, Extension(pool): Extension<PgPool>) -> Html<String> {
-    let mut query = format!("%{}%", params.get("query").unwrap());
-    if params.get("query").unwrap() == "" {
-        query = format!("%pasta%")
+async fn search_pretty_results(Query(params): Query<HashMap<String, String>>, State(pool): State<PgPool>) -> Html<String> {
+    let mut query = params.get("query").cloned().unwrap_or_default();
+    if query.is_empty() {
+        query = "pasta".to_string()
     }
-    let result = search_for_product(query, &"name".to_string(), pool).await.unwrap();
-
-    let results_html: String = result.iter()
-        .map(|product| {
+    let filters = SearchFilters::default();
+    let search = search_fingerprint(&query, &filters, SortField::Relevance, SortOrder::Desc);
+    let cursor = params.get("cursor")
+        .and_then(|token| Cursor::decode(token))
+        .filter(|cursor| cursor.is_for(&search));
+    let is_next_page = cursor.is_some();
+    let page = search_for_product(query.clone(), &filters, SortField::Relevance, SortOrder::Desc, DEFAULT_LIMIT, cursor.as_ref(), pool).await.unwrap();
+
+    // The last row of each page fetches the next one once it scrolls into
+    // view, appending it straight after itself.
+    let next_page_attrs = page.next_cursor.map(|cursor| {
+        let next_url = serde_urlencoded::to_string([("query", query.as_str()), ("cursor", &cursor.encode())]).unwrap();
+        format!(r#" hx-get="/search-pretty-results?{next_url}" hx-trigger="revealed" hx-swap="afterend""#)
+    });
+    let last_index = page.results.len().saturating_sub(1);
+    let results_html: String = page.results.iter()
+        .enumerate()
+        .map(|(i, result)| {
+            let product = &result.product;
             let name = &product.name;
             let price = &product.price;
             let brand = &product.brand;
@@ -349,15 +360,19 @@ async fn search_pretty_results(Query(params): Query<HashMap<String, String>>, Ex
                 "tesco" => "blue",
                 _ => "black"
             };
-            format!(r#"<tr><td><img src="{image}" width=24 height=24></td><td>{name}</td><td style="color: {color};">£{price:.2}</td><td>{brand}</td><td>{rating:.2?}</td></tr>"#)
+            let attrs = if i == last_index { next_page_attrs.as_deref().unwrap_or("") } else { "" };
+            format!(r#"<tr{attrs}><td><img src="{image}" width=24 height=24></td><td>{name}</td><td style="color: {color};">£{price:.2}</td><td>{brand}</td><td>{rating:.2?}</td></tr>"#)
         })
         .collect::<Vec<String>>()
         .join("\n");
+    if is_next_page {
+        return Html(results_html)
+    }
     let output_html = format!(r#"
     <table class="table" id="search-results">
     {results_html}
     </table>"#);
-    return Html(output_html)
+    Html(output_html)
 }
 
 
@@ -368,7 +383,7 @@ struct SearchTemplate {}
 
 async fn search_pretty_page() -> Html<String> {
     let search_template = SearchTemplate {};
-    return Html(search_template.render().unwrap())
+    Html(search_template.render().unwrap())
 }
 
 #[derive(Template)]
@@ -381,7 +396,7 @@ struct DebugDashboardTemplate {
 }
 
 
-async fn debug_dashboard(Extension(pool): Extension<PgPool>) -> Html<String> {
+async fn debug_dashboard(State(pool): State<PgPool>) -> Html<String> {
     let result: (sqlx::types::Json<DebugInfo>,) = sqlx::query_as(
         "SELECT json_build_object(
             'total', (SELECT COUNT(*) FROM product),
@@ -399,51 +414,118 @@ async fn debug_dashboard(Extension(pool): Extension<PgPool>) -> Html<String> {
         unique:  debug_info.unique,
         notyetscraped:  debug_info.notyetscraped
     };
-    return Html(debug_dashboard_template.render().unwrap())
+    Html(debug_dashboard_template.render().unwrap())
 }
 
 
 
-async fn verify_header_api_key(mut req: Request, next: Next) -> Result<Response, StatusCode> {
+async fn verify_header_api_key(State(pool): State<PgPool>, mut req: Request, next: Next) -> Response {
     let auth_header = req.headers()
         .get(http::header::AUTHORIZATION)
         .and_then(|header| header.to_str().ok())
-        .and_then(|header| header.strip_prefix("Bearer "))
-        .and_then(|api_key| Uuid::try_parse(api_key).ok());
+        .and_then(|header| header.strip_prefix("Bearer "));
 
     let auth_header = if let Some(auth_header) = auth_header {
         auth_header
     } else {
-        return Err(StatusCode::UNAUTHORIZED);
+        return AppError::MissingKey.into_response();
+    };
+    let endpoint = match req.extensions().get::<MatchedPath>() {
+        Some(path) => format!("{} {}", req.method(), path.as_str()),
+        None => format!("{} {}", req.method(), req.uri().path()),
     };
-    let pool = db_conn().await;
-    let credits_period: Option<CreditsPeriod> = sqlx::query_as(r#"
-        UPDATE credits_period SET credits_used = credits_used + 1
-        FROM api_key
-        WHERE credits_period.users_id = api_key.users_id AND key = $1
-        RETURNING credits_period.*
-        "#)
-        .bind(auth_header)
-        .fetch_optional(&pool)
-        .await.unwrap();
-    if let Some(credits_period) = credits_period {
-        println!("{}/{}", credits_period.credits_used, credits_period.credits_allocated);
-        if credits_period.credits_used <= credits_period.credits_allocated {
-            Ok(next.run(req).await)
-        } else {
-            Err(StatusCode::UNAUTHORIZED)
+    let charge = match usage::charge(&keys::hash_key(auth_header), &endpoint, &pool).await {
+        Ok(charge) => charge,
+        Err(err) => return AppError::from(err).into_response(),
+    };
+    match charge {
+        Charge::Charged(caller) => {
+            let credits_remaining = caller.credits_remaining;
+            req.extensions_mut().insert(caller);
+            let mut response = next.run(req).await;
+            response.headers_mut().insert("X-Credits-Remaining", credits_remaining.into());
+            response
         }
-    } else {
-        Err(StatusCode::UNAUTHORIZED)
+        Charge::PeriodExhausted => {
+            let mut response = AppError::CreditsExhausted.into_response();
+            response.headers_mut().insert("X-Credits-Remaining", 0.into());
+            response
+        }
+        Charge::KeyCapReached => {
+            let mut response = AppError::KeyCapReached.into_response();
+            response.headers_mut().insert("X-Credits-Remaining", 0.into());
+            response
+        }
+        Charge::UnknownKey => AppError::InvalidKey.into_response(),
     }
+}
 
 
-    // if let Some(current_user) = current_user_for_api_key(auth_header).await {
-    //     // insert the current user into a request extension so the handler can
-    //     // extract it
-    //     req.extensions_mut().insert(current_user);
-    //     Ok(next.run(req).await)
-    // } else {
-    //     Err(StatusCode::UNAUTHORIZED)
-    // }
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        str::FromStr,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+    use sqlx::postgres::PgConnectOptions;
+    use tokio::{net::TcpListener, task::JoinSet};
+
+    use super::*;
+
+    const MAX_CONNECTIONS: u32 = 3;
+    const REQUESTS: usize = 60;
+
+    // Fires more concurrent requests than the pool has connections at
+    // routes that query the database, and checks they all queue on the one
+    // pool instead of opening connections of their own. Run it with
+    // `DATABASE_URL=... cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL pointing at a database"]
+    async fn concurrent_requests_share_one_pool() {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let config = Config {
+            database: PgConnectOptions::from_str(&url).unwrap(),
+            bind_address: SocketAddr::from(([127, 0, 0, 1], 0)),
+            db_max_connections: MAX_CONNECTIONS,
+            db_min_connections: 0,
+            db_acquire_timeout_secs: 30,
+            secure_cookies: false,
+            session_expiry_hours: 24,
+            log_level: "info".to_string(),
+            allow_private_webhooks: false,
+        };
+        let pool = db::connect(&config).await.unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        let state = AppState { pool: pool.clone(), rate_limiter: RateLimiter::new(), config: Arc::new(config) };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app(state).into_make_service()).await });
+
+        let peak = Arc::new(AtomicU32::new(0));
+        let sampler = tokio::spawn({
+            let (pool, peak) = (pool.clone(), peak.clone());
+            async move {
+                loop {
+                    peak.fetch_max(pool.size(), Ordering::Relaxed);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(1)).await;
+                }
+            }
+        });
+
+        let client = reqwest::Client::new();
+        let mut requests = JoinSet::new();
+        for i in 0..REQUESTS {
+            let path = if i % 2 == 0 { "/api/plans" } else { "/inflation" };
+            requests.spawn(client.get(format!("http://{address}{path}")).send());
+        }
+        while let Some(response) = requests.join_next().await {
+            assert_eq!(response.unwrap().unwrap().status(), reqwest::StatusCode::OK);
+        }
+        sampler.abort();
+
+        let peak = peak.load(Ordering::Relaxed).max(pool.size());
+        assert!(peak <= MAX_CONNECTIONS, "pool grew to {peak} connections");
+        assert!(peak > 0);
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    extract::Query,
+    extract::rejection::QueryRejection,
+    response::{IntoResponse, Response},
+    Json,
+    extract::State,
+};
+use serde::{Serialize, Deserialize};
+use sqlx::PgPool;
+use tokio::time::{interval, Duration};
+
+use crate::error::{bad_request, AppError};
+use crate::units::{parse_pack_size, strip_pack_sizes, PackSize};
+
+
+// Similarity matches below this confidence get their own canonical product.
+const MATCH_THRESHOLD: f64 = 0.75;
+
+// Supermarket own-label ranges are treated as one brand so that e.g. Asda's
+// and Tesco's own penne can be compared against each other.
+const OWN_LABEL_BRANDS: [&str; 3] = ["asda", "sainsburys", "tesco"];
+
+const STOP_WORDS: [&str; 8] = ["asda", "sainsburys", "tesco", "s", "the", "and", "with", "of"];
+
+
+#[derive(sqlx::FromRow)]
+struct Listing {
+    seller: String,
+    sku: i64,
+    gtin: Option<i64>,
+    name: String,
+    brand: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct CanonicalRow {
+    id: i64,
+    gtin: Option<i64>,
+    name: String,
+    brand: String,
+}
+
+struct Features {
+    tokens: HashSet<String>,
+    brand: String,
+    pack: Option<PackSize>,
+}
+
+struct Candidate {
+    id: i64,
+    features: Features,
+    sellers: HashSet<String>,
+}
+
+// Canonical products held in memory while matching, indexed by GTIN and by
+// name token so each listing is only scored against plausible candidates.
+#[derive(Default)]
+struct Catalogue {
+    by_gtin: HashMap<i64, usize>,
+    by_token: HashMap<String, Vec<usize>>,
+    candidates: Vec<Candidate>,
+}
+
+
+fn normalise_brand(brand: &str) -> String {
+    let brand: String = brand.to_lowercase().chars().filter(|c| c.is_alphanumeric()).collect();
+    if OWN_LABEL_BRANDS.contains(&brand.as_str()) {
+        "own label".to_string()
+    } else {
+        brand
+    }
+}
+
+fn features(name: &str, brand: &str) -> Features {
+    let brand_tokens: HashSet<String> = brand
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_string)
+        .collect();
+    let tokens = strip_pack_sizes(&name.to_lowercase())
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty() && !token.chars().any(|c| c.is_ascii_digit()))
+        .filter(|token| !STOP_WORDS.contains(token) && !brand_tokens.contains(*token))
+        .map(str::to_string)
+        .collect();
+    Features {
+        tokens,
+        brand: normalise_brand(brand),
+        pack: parse_pack_size(name),
+    }
+}
+
+// Confidence in [0, 1] that two listings are the same item. Listings with
+// clearly different pack sizes never match.
+fn similarity(a: &Features, b: &Features) -> f64 {
+    let pack_score = match (a.pack, b.pack) {
+        (Some(x), Some(y)) if x.is_compatible(&y) => 1.0,
+        (Some(_), Some(_)) => return 0.0,
+        _ => 0.5,
+    };
+    let shared = a.tokens.intersection(&b.tokens).count() as f64;
+    let smallest = a.tokens.len().min(b.tokens.len()) as f64;
+    let union = a.tokens.union(&b.tokens).count() as f64;
+    let name_score = if smallest == 0.0 {
+        0.0
+    } else {
+        // Average of Jaccard and overlap, so a terser name on one site isn't
+        // punished as hard as a genuinely different one.
+        (shared / union + shared / smallest) / 2.0
+    };
+    let brand_score = if a.brand == b.brand { 1.0 } else { 0.0 };
+    0.6 * name_score + 0.25 * brand_score + 0.15 * pack_score
+}
+
+impl Catalogue {
+    fn add(&mut self, id: i64, gtin: Option<i64>, features: Features) -> usize {
+        let idx = self.candidates.len();
+        if let Some(gtin) = gtin {
+            self.by_gtin.insert(gtin, idx);
+        }
+        for token in &features.tokens {
+            self.by_token.entry(token.clone()).or_default().push(idx);
+        }
+        self.candidates.push(Candidate { id, features, sellers: HashSet::new() });
+        idx
+    }
+
+    // A listing with a GTIN only ever matches on it. Others match the most
+    // similar candidate the seller doesn't already carry, as long as it's at
+    // least MATCH_THRESHOLD similar.
+    fn best_match(&self, seller: &str, gtin: Option<i64>, features: &Features) -> Option<(usize, &'static str, f64)> {
+        if let Some(gtin) = gtin {
+            return self.by_gtin.get(&gtin).map(|&idx| (idx, "gtin", 1.0))
+        }
+        let nearby: HashSet<usize> = features.tokens
+            .iter()
+            .filter_map(|token| self.by_token.get(token))
+            .flatten()
+            .copied()
+            .collect();
+        nearby
+            .into_iter()
+            .filter(|&idx| !self.candidates[idx].sellers.contains(seller))
+            .map(|idx| (idx, similarity(features, &self.candidates[idx].features)))
+            .filter(|&(_, score)| score >= MATCH_THRESHOLD)
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|(idx, score)| (idx, "similarity", score))
+    }
+}
+
+
+// Links every listing that isn't matched yet to a canonical product: by GTIN
+// when the listing has one, otherwise to the most similar canonical product
+// not already carried by the same seller. Returns how many were linked.
+pub async fn run_matching(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let canonical_rows: Vec<CanonicalRow> = sqlx::query_as("SELECT id, gtin, name, brand FROM canonical_product")
+        .fetch_all(&mut *tx).await?;
+    let carried: Vec<(i64, String)> = sqlx::query_as("SELECT canonical_product_id, seller FROM product_match")
+        .fetch_all(&mut *tx).await?;
+    // GTIN listings go first so Sainsbury's rows can attach to them.
+    let listings: Vec<Listing> = sqlx::query_as(
+        "SELECT latest.seller, latest.sku, latest.gtin, latest.name, latest.brand FROM (
+            SELECT DISTINCT ON (seller, sku) seller, sku, gtin, name, brand
+            FROM product
+            ORDER BY seller, sku, scraped DESC
+        ) latest
+        LEFT JOIN product_match USING (seller, sku)
+        WHERE product_match.seller IS NULL
+        ORDER BY latest.gtin IS NULL, latest.seller, latest.sku"
+    ).fetch_all(&mut *tx).await?;
+
+    let mut catalogue = Catalogue::default();
+    let mut index_of: HashMap<i64, usize> = HashMap::new();
+    for row in canonical_rows {
+        let idx = catalogue.add(row.id, row.gtin, features(&row.name, &row.brand));
+        index_of.insert(row.id, idx);
+    }
+    for (canonical_id, seller) in carried {
+        if let Some(&idx) = index_of.get(&canonical_id) {
+            catalogue.candidates[idx].sellers.insert(seller);
+        }
+    }
+
+    let linked = listings.len();
+    for listing in listings {
+        let listing_features = features(&listing.name, &listing.brand);
+        let matched = catalogue.best_match(&listing.seller, listing.gtin, &listing_features);
+
+        let (idx, method, confidence) = match matched {
+            Some(found) => found,
+            None => {
+                let (id,): (i64,) = sqlx::query_as(
+                    "INSERT INTO canonical_product (gtin, name, brand) VALUES ($1, $2, $3) RETURNING id"
+                )
+                .bind(listing.gtin)
+                .bind(&listing.name)
+                .bind(&listing.brand)
+                .fetch_one(&mut *tx).await?;
+                let method = if listing.gtin.is_some() { "gtin" } else { "similarity" };
+                (catalogue.add(id, listing.gtin, listing_features), method, 1.0)
+            }
+        };
+
+        sqlx::query(
+            "INSERT INTO product_match (seller, sku, canonical_product_id, method, confidence)
+            VALUES ($1, $2, $3, $4, $5)"
+        )
+        .bind(&listing.seller)
+        .bind(listing.sku)
+        .bind(catalogue.candidates[idx].id)
+        .bind(method)
+        .bind(confidence)
+        .execute(&mut *tx).await?;
+        catalogue.candidates[idx].sellers.insert(listing.seller);
+    }
+
+    tx.commit().await?;
+    Ok(linked)
+}
+
+
+pub async fn run_periodically(pool: PgPool, every: Duration) {
+    let mut ticker = interval(every);
+    loop {
+        ticker.tick().await;
+        match run_matching(&pool).await {
+            Ok(0) => {}
+            Ok(linked) => tracing::info!("matched {} new listings", linked),
+            Err(err) => tracing::error!("product matching failed: {}", err),
+        }
+    }
+}
+
+
+#[derive(Deserialize)]
+pub struct CompareParams {
+    q: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct OfferRow {
+    canonical_product_id: i64,
+    canonical_name: String,
+    canonical_brand: String,
+    canonical_gtin: Option<i64>,
+    seller: String,
+    sku: i64,
+    name: String,
+    price: f64,
+    availability: String,
+    url: String,
+    confidence: f64,
+}
+
+#[derive(Serialize)]
+pub struct Offer {
+    pub seller: String,
+    pub sku: i64,
+    pub name: String,
+    pub price: f64,
+    pub availability: String,
+    pub url: String,
+    pub confidence: f64,
+}
+
+#[derive(Serialize)]
+pub struct ComparedItem {
+    pub canonical_product_id: i64,
+    pub name: String,
+    pub brand: String,
+    pub gtin: Option<i64>,
+    // Cheapest current listing per seller, cheapest seller first.
+    pub offers: Vec<Offer>,
+}
+
+
+pub async fn compare_prices(q: &str, pool: &PgPool) -> Result<Vec<ComparedItem>, sqlx::Error> {
+    let rows: Vec<OfferRow> = sqlx::query_as(
+        "WITH hits AS (
+            SELECT product_match.canonical_product_id
+            FROM (
+                SELECT DISTINCT seller, sku FROM product
+                WHERE search_vector @@ websearch_to_tsquery('english', $1) OR $1 <% name
+            ) matching
+            JOIN product_match USING (seller, sku)
+            CROSS JOIN LATERAL (
+                SELECT name, search_vector FROM product
+                WHERE product.seller = matching.seller AND product.sku = matching.sku
+                ORDER BY scraped DESC
+                LIMIT 1
+            ) latest
+            -- Only listings whose current name still matches.
+            WHERE latest.search_vector @@ websearch_to_tsquery('english', $1) OR $1 <% latest.name
+            GROUP BY product_match.canonical_product_id
+            ORDER BY COUNT(DISTINCT matching.seller) DESC, product_match.canonical_product_id
+            LIMIT 20
+        )
+        SELECT DISTINCT ON (canonical_product.id, product_match.seller)
+            canonical_product.id AS canonical_product_id,
+            canonical_product.name AS canonical_name,
+            canonical_product.brand AS canonical_brand,
+            canonical_product.gtin AS canonical_gtin,
+            product_match.seller, product_match.sku, latest.name, latest.price, latest.availability, latest.url,
+            product_match.confidence
+        FROM hits
+        JOIN canonical_product ON canonical_product.id = hits.canonical_product_id
+        JOIN product_match ON product_match.canonical_product_id = canonical_product.id
+        CROSS JOIN LATERAL (
+            SELECT name, price, availability, url FROM product
+            WHERE product.seller = product_match.seller AND product.sku = product_match.sku
+            ORDER BY scraped DESC
+            LIMIT 1
+        ) latest
+        WHERE latest.price > 0
+        ORDER BY canonical_product.id, product_match.seller, latest.price"
+    )
+    .bind(q)
+    .fetch_all(pool).await?;
+
+    let mut items: Vec<ComparedItem> = Vec::new();
+    for row in rows {
+        let offer = Offer {
+            seller: row.seller,
+            sku: row.sku,
+            name: row.name,
+            price: row.price,
+            availability: row.availability,
+            url: row.url,
+            confidence: row.confidence,
+        };
+        match items.last_mut() {
+            Some(item) if item.canonical_product_id == row.canonical_product_id => item.offers.push(offer),
+            _ => items.push(ComparedItem {
+                canonical_product_id: row.canonical_product_id,
+                name: row.canonical_name,
+                brand: row.canonical_brand,
+                gtin: row.canonical_gtin,
+                offers: vec![offer],
+            }),
+        }
+    }
+    for item in items.iter_mut() {
+        item.offers.sort_by(|a, b| a.price.total_cmp(&b.price));
+    }
+    // Items stocked by the most sellers are the most useful comparisons.
+    items.sort_by(|a, b| b.offers.len().cmp(&a.offers.len()).then(a.canonical_product_id.cmp(&b.canonical_product_id)));
+    Ok(items)
+}
+
+
+pub async fn compare(params: Result<Query<CompareParams>, QueryRejection>, State(pool): State<PgPool>) -> Response {
+    let Query(params) = match params {
+        Ok(params) => params,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    match compare_prices(&params.q, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(items) => Json(items).into_response(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(features: &Features) -> Vec<&str> {
+        let mut tokens: Vec<&str> = features.tokens.iter().map(String::as_str).collect();
+        tokens.sort();
+        tokens
+    }
+
+    #[test]
+    fn features_drop_brand_stop_words_and_pack_sizes() {
+        let penne = features("Tesco Penne Pasta with the Tomato 500g", "Tesco");
+        assert_eq!(tokens(&penne), ["pasta", "penne", "tomato"]);
+        assert_eq!(penne.brand, "own label");
+        assert_eq!(penne.pack.map(|pack| pack.total()), Some(500.0));
+
+        let beans = features("Heinz Baked Beans 4 x 415g", "Heinz");
+        assert_eq!(tokens(&beans), ["baked", "beans"]);
+        assert_eq!(beans.brand, "heinz");
+    }
+
+    #[test]
+    fn own_label_brands_count_as_the_same_brand() {
+        assert_eq!(normalise_brand("Sainsbury's"), "own label");
+        assert_eq!(normalise_brand("ASDA"), "own label");
+        assert_eq!(normalise_brand("Barilla"), "barilla");
+    }
+
+    #[test]
+    fn similarity_of_the_same_item_at_two_sellers_passes_the_threshold() {
+        let asda = features("ASDA Penne Pasta 500g", "ASDA");
+        let tesco = features("Tesco Penne Pasta 500G", "Tesco");
+        assert!(similarity(&asda, &tesco) >= MATCH_THRESHOLD);
+    }
+
+    #[test]
+    fn similarity_is_zero_for_different_pack_sizes() {
+        let small = features("Barilla Penne 500g", "Barilla");
+        let large = features("Barilla Penne 1kg", "Barilla");
+        assert_eq!(similarity(&small, &large), 0.0);
+    }
+
+    #[test]
+    fn similarity_of_different_items_stays_below_the_threshold() {
+        let penne = features("Barilla Penne 500g", "Barilla");
+        let fusilli = features("Barilla Fusilli 500g", "Barilla");
+        assert!(similarity(&penne, &fusilli) < MATCH_THRESHOLD);
+    }
+
+    #[test]
+    fn gtin_matches_win_whatever_the_name() {
+        let mut catalogue = Catalogue::default();
+        catalogue.add(1, Some(5000157024671), features("Heinz Baked Beans 415g", "Heinz"));
+        let listing = features("Beanz in Tomato Sauce", "Heinz");
+        assert_eq!(catalogue.best_match("asda", Some(5000157024671), &listing), Some((0, "gtin", 1.0)));
+    }
+
+    #[test]
+    fn unknown_gtins_dont_fall_back_to_similarity() {
+        let mut catalogue = Catalogue::default();
+        catalogue.add(1, None, features("Heinz Baked Beans 415g", "Heinz"));
+        let listing = features("Heinz Baked Beans 415g", "Heinz");
+        assert_eq!(catalogue.best_match("asda", Some(5000157024671), &listing), None);
+        assert_eq!(catalogue.best_match("asda", None, &listing).map(|(idx, via, _)| (idx, via)), Some((0, "similarity")));
+    }
+
+    #[test]
+    fn matches_below_the_threshold_are_left_unmatched() {
+        let mut catalogue = Catalogue::default();
+        catalogue.add(1, None, features("Barilla Penne 500g", "Barilla"));
+        let listing = features("De Cecco Penne Rigate 500g", "De Cecco");
+        let score = similarity(&listing, &catalogue.candidates[0].features);
+        assert!(score > 0.0 && score < MATCH_THRESHOLD);
+        assert_eq!(catalogue.best_match("asda", None, &listing), None);
+    }
+
+    #[test]
+    fn candidates_the_seller_already_carries_are_skipped() {
+        let mut catalogue = Catalogue::default();
+        let idx = catalogue.add(1, None, features("Tesco Penne Pasta 500g", "Tesco"));
+        catalogue.candidates[idx].sellers.insert("tesco".to_string());
+        let listing = features("Tesco Penne Pasta 500g", "Tesco");
+        assert_eq!(catalogue.best_match("tesco", None, &listing), None);
+        assert!(catalogue.best_match("asda", None, &listing).is_some());
+    }
+}
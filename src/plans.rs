@@ -0,0 +1,152 @@
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+    extract::State,
+};
+use chrono::{Datelike, Months, NaiveDate};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+
+use crate::db::CreditsPeriod;
+use crate::error::AppError;
+
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct Plan {
+    pub name: String,
+    pub monthly_credits: i32,
+    pub rate_limit_per_minute: i32,
+    pub rate_limit_burst: i32,
+}
+
+
+// `months` whole months after `anchor`, clamped to the end of shorter
+// months: a period anchored on 31 January starts on 28 (or 29) February
+// and then on 31 March again.
+fn months_after(anchor: NaiveDate, months: u32) -> NaiveDate {
+    anchor.checked_add_months(Months::new(months)).expect("date out of range")
+}
+
+// The monthly period containing `today` for a user whose periods started
+// on `anchor`. Periods run from one monthly anniversary of the anchor to
+// the day before the next.
+pub fn period_for(anchor: NaiveDate, today: NaiveDate) -> (NaiveDate, NaiveDate) {
+    let mut months = ((today.year() - anchor.year()) * 12 + today.month() as i32 - anchor.month() as i32).max(0) as u32;
+    if months > 0 && months_after(anchor, months) > today {
+        months -= 1;
+    }
+    let start = months_after(anchor, months);
+    let end = months_after(anchor, months + 1).pred_opt().expect("date out of range");
+    (start, end)
+}
+
+// The user's credits period covering `today`, locked for update. When the
+// last one has run out a new one is created with the allowance of the
+// user's current plan, so plan changes take effect from the next period.
+pub async fn current_period(conn: &mut PgConnection, users_id: i64, today: NaiveDate) -> Result<CreditsPeriod, sqlx::Error> {
+    let period: Option<CreditsPeriod> = sqlx::query_as(
+        "SELECT * FROM credits_period
+        WHERE users_id = $1 AND start_date <= $2 AND end_date >= $2
+        ORDER BY start_date DESC
+        LIMIT 1
+        FOR UPDATE"
+    )
+    .bind(users_id)
+    .bind(today)
+    .fetch_optional(&mut *conn).await?;
+    if let Some(period) = period {
+        return Ok(period)
+    }
+
+    let anchor: Option<NaiveDate> = sqlx::query_scalar("SELECT MIN(start_date) FROM credits_period WHERE users_id = $1")
+        .bind(users_id)
+        .fetch_one(&mut *conn).await?;
+    let (start_date, end_date) = period_for(anchor.unwrap_or(today), today);
+    sqlx::query(
+        "INSERT INTO credits_period (users_id, start_date, end_date, credits_allocated)
+        SELECT users.id, $2, $3, plan.monthly_credits
+        FROM users
+        JOIN plan ON plan.name = users.plan
+        WHERE users.id = $1
+        ON CONFLICT (users_id, start_date) DO NOTHING"
+    )
+    .bind(users_id)
+    .bind(start_date)
+    .bind(end_date)
+    .execute(&mut *conn).await?;
+    sqlx::query_as("SELECT * FROM credits_period WHERE users_id = $1 AND start_date = $2 FOR UPDATE")
+        .bind(users_id)
+        .bind(start_date)
+        .fetch_one(&mut *conn).await
+}
+
+
+pub async fn list_plans(State(pool): State<PgPool>) -> Response {
+    let plans: Result<Vec<Plan>, sqlx::Error> = sqlx::query_as("SELECT name, monthly_credits, rate_limit_per_minute, rate_limit_burst FROM plan ORDER BY monthly_credits")
+        .fetch_all(&pool).await;
+    match plans {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(plans) => Json(plans).into_response(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn periods_anchored_on_the_31st_clamp_to_short_months() {
+        let anchor = date(2025, 1, 31);
+        let cases = [
+            // today, start, end
+            (date(2025, 1, 31), date(2025, 1, 31), date(2025, 2, 27)),
+            (date(2025, 2, 27), date(2025, 1, 31), date(2025, 2, 27)),
+            (date(2025, 2, 28), date(2025, 2, 28), date(2025, 3, 30)),
+            (date(2025, 3, 30), date(2025, 2, 28), date(2025, 3, 30)),
+            (date(2025, 3, 31), date(2025, 3, 31), date(2025, 4, 29)),
+            (date(2025, 4, 30), date(2025, 4, 30), date(2025, 5, 30)),
+            (date(2025, 12, 31), date(2025, 12, 31), date(2026, 1, 30)),
+            (date(2026, 1, 15), date(2025, 12, 31), date(2026, 1, 30)),
+            (date(2026, 1, 31), date(2026, 1, 31), date(2026, 2, 27)),
+        ];
+        for (today, start, end) in cases {
+            assert_eq!(period_for(anchor, today), (start, end), "today {today}");
+        }
+    }
+
+    #[test]
+    fn leap_years_start_on_the_29th_of_february() {
+        let anchor = date(2024, 1, 31);
+        assert_eq!(period_for(anchor, date(2024, 2, 28)), (date(2024, 1, 31), date(2024, 2, 28)));
+        assert_eq!(period_for(anchor, date(2024, 2, 29)), (date(2024, 2, 29), date(2024, 3, 30)));
+        assert_eq!(period_for(anchor, date(2024, 3, 31)), (date(2024, 3, 31), date(2024, 4, 29)));
+    }
+
+    #[test]
+    fn today_on_the_anchor_is_the_first_period() {
+        let anchor = date(2025, 6, 15);
+        assert_eq!(period_for(anchor, anchor), (date(2025, 6, 15), date(2025, 7, 14)));
+    }
+
+    #[test]
+    fn periods_follow_on_without_gaps_or_overlaps() {
+        for anchor in [date(2023, 1, 31), date(2023, 1, 29), date(2023, 3, 30), date(2023, 12, 31)] {
+            let mut today = anchor;
+            let mut period = period_for(anchor, today);
+            while today < date(2026, 1, 1) {
+                let (start, end) = period_for(anchor, today);
+                assert!(start <= today && today <= end, "{anchor}: {today} outside {start}..{end}");
+                if start != period.0 {
+                    assert_eq!(start, period.1.succ_opt().unwrap(), "{anchor}: gap before {start}");
+                    period = (start, end);
+                }
+                today = today.succ_opt().unwrap();
+            }
+        }
+    }
+}
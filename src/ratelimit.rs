@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use axum::{
+    extract::Request,
+    http::{self, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    extract::State,
+};
+use sqlx::PgPool;
+use tokio::time::interval;
+
+use crate::error::AppError;
+use crate::keys;
+
+
+// How long a bucket keeps the limits of the key's plan before they're
+// looked up again, so plan changes apply without a restart.
+const LIMITS_TTL: Duration = Duration::from_secs(60);
+
+
+#[derive(sqlx::FromRow, Clone, Copy)]
+struct Limits {
+    rate_limit_per_minute: i32,
+    rate_limit_burst: i32,
+}
+
+impl Limits {
+    fn per_second(&self) -> f64 {
+        self.rate_limit_per_minute as f64 / 60.0
+    }
+}
+
+struct Bucket {
+    limits: Limits,
+    limits_checked_at: Instant,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn new(limits: Limits, now: Instant) -> Self {
+        Bucket { limits, limits_checked_at: now, tokens: limits.rate_limit_burst as f64, updated_at: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limits.per_second()).min(self.limits.rate_limit_burst as f64);
+        self.updated_at = now;
+    }
+
+    // Seconds until `tokens` are available again.
+    fn secs_until(&self, tokens: f64) -> u64 {
+        ((tokens - self.tokens).max(0.0) / self.limits.per_second()).ceil() as u64
+    }
+
+    // Takes a token if there's one left after refilling up to `now`.
+    fn take(&mut self, now: Instant) -> Decision {
+        self.refill(now);
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+        let quota = Quota {
+            limit: self.limits.rate_limit_burst,
+            remaining: self.tokens.floor() as u64,
+            reset_secs: self.secs_until(self.limits.rate_limit_burst as f64),
+            retry_after_secs: self.secs_until(1.0).max(1),
+        };
+        if allowed { Decision::Allowed(quota) } else { Decision::Limited(quota) }
+    }
+}
+
+enum Decision {
+    Allowed(Quota),
+    Limited(Quota),
+}
+
+struct Quota {
+    limit: i32,
+    remaining: u64,
+    reset_secs: u64,
+    retry_after_secs: u64,
+}
+
+// Token buckets per API key hash, shared by all requests, along with when
+// keys that turned out not to exist were looked up.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    unknown: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Takes a token from the key's bucket, refreshing its limits first if
+    // they're stale. Returns None for keys that aren't known (or have been
+    // revoked). Those are remembered for LIMITS_TTL, so a flood of bogus
+    // keys doesn't turn into a flood of lookups.
+    async fn take(&self, key_hash: &str, pool: &PgPool) -> Result<Option<Decision>, sqlx::Error> {
+        let now = Instant::now();
+        if self.unknown.lock().unwrap().get(key_hash).is_some_and(|checked_at| now.duration_since(*checked_at) < LIMITS_TTL) {
+            return Ok(None)
+        }
+        let stale = match self.buckets.lock().unwrap().get(key_hash) {
+            Some(bucket) => now.duration_since(bucket.limits_checked_at) >= LIMITS_TTL,
+            None => true,
+        };
+        if stale {
+            let limits: Option<Limits> = sqlx::query_as(
+                "SELECT plan.rate_limit_per_minute, plan.rate_limit_burst
+                FROM api_key
+                JOIN users ON users.id = api_key.users_id
+                JOIN plan ON plan.name = users.plan
+                WHERE api_key.key_hash = $1 AND api_key.revoked_at IS NULL"
+            )
+            .bind(key_hash)
+            .fetch_optional(pool).await?;
+            let Some(limits) = limits else {
+                self.buckets.lock().unwrap().remove(key_hash);
+                self.unknown.lock().unwrap().insert(key_hash.to_owned(), now);
+                return Ok(None)
+            };
+            self.unknown.lock().unwrap().remove(key_hash);
+            let mut buckets = self.buckets.lock().unwrap();
+            let bucket = buckets.entry(key_hash.to_owned()).or_insert_with(|| Bucket::new(limits, now));
+            bucket.refill(now);
+            bucket.limits = limits;
+            bucket.limits_checked_at = now;
+            bucket.tokens = bucket.tokens.min(limits.rate_limit_burst as f64);
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        Ok(buckets.get_mut(key_hash).map(|bucket| bucket.take(now)))
+    }
+
+    // Drops buckets that have refilled completely by `now`, which behave
+    // the same as a new bucket would, and unknown keys due a new lookup.
+    fn prune(&self, now: Instant) {
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.limits.rate_limit_burst as f64
+        });
+        self.unknown.lock().unwrap().retain(|_, checked_at| now.duration_since(*checked_at) < LIMITS_TTL);
+    }
+}
+
+
+fn set_quota_headers(response: &mut Response, quota: &Quota) {
+    let headers = response.headers_mut();
+    headers.insert("X-RateLimit-Limit", HeaderValue::from(quota.limit));
+    headers.insert("X-RateLimit-Remaining", HeaderValue::from(quota.remaining));
+    headers.insert("X-RateLimit-Reset", HeaderValue::from(quota.reset_secs));
+}
+
+fn limited_response(quota: &Quota) -> Response {
+    let mut response = AppError::RateLimited.into_response();
+    set_quota_headers(&mut response, quota);
+    response.headers_mut().insert(http::header::RETRY_AFTER, HeaderValue::from(quota.retry_after_secs));
+    response
+}
+
+// Runs ahead of the API key check, so calls that are turned away here
+// aren't charged any credits. Unknown keys are turned away here too.
+pub async fn rate_limit(
+    State(limiter): State<RateLimiter>,
+    State(pool): State<PgPool>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let key_hash = req.headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(keys::hash_key);
+    let Some(key_hash) = key_hash else {
+        return next.run(req).await
+    };
+    match limiter.take(&key_hash, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        // The key check would turn it away too, after another lookup.
+        Ok(None) => AppError::InvalidKey.into_response(),
+        Ok(Some(Decision::Allowed(quota))) => {
+            let mut response = next.run(req).await;
+            set_quota_headers(&mut response, &quota);
+            response
+        }
+        Ok(Some(Decision::Limited(quota))) => limited_response(&quota),
+    }
+}
+
+pub async fn prune_periodically(limiter: RateLimiter, every: Duration) {
+    let mut interval = interval(every);
+    loop {
+        interval.tick().await;
+        limiter.prune(Instant::now());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    // One token a second, up to 5.
+    const LIMITS: Limits = Limits { rate_limit_per_minute: 60, rate_limit_burst: 5 };
+
+    fn split(decision: Decision) -> (bool, Quota) {
+        match decision {
+            Decision::Allowed(quota) => (true, quota),
+            Decision::Limited(quota) => (false, quota),
+        }
+    }
+
+    #[test]
+    fn new_buckets_start_full() {
+        let now = Instant::now();
+        let bucket = Bucket::new(LIMITS, now);
+        assert_eq!(bucket.tokens, 5.0);
+        assert_eq!(bucket.secs_until(5.0), 0);
+    }
+
+    #[test]
+    fn refill_is_capped_at_the_burst() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(LIMITS, now);
+        bucket.tokens = 1.0;
+        bucket.refill(now + Duration::from_millis(2500));
+        assert_eq!(bucket.tokens, 3.5);
+        assert_eq!(bucket.secs_until(5.0), 2);
+        bucket.refill(now + Duration::from_secs(3600));
+        assert_eq!(bucket.tokens, 5.0);
+    }
+
+    #[test]
+    fn taking_tokens_reports_remaining_and_reset() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(LIMITS, now);
+        for remaining in (0..5).rev() {
+            let (allowed, quota) = split(bucket.take(now));
+            assert!(allowed);
+            assert_eq!(quota.limit, 5);
+            assert_eq!(quota.remaining, remaining);
+            assert_eq!(quota.reset_secs, 5 - remaining);
+        }
+
+        let (allowed, quota) = split(bucket.take(now));
+        assert!(!allowed);
+        assert_eq!((quota.remaining, quota.reset_secs, quota.retry_after_secs), (0, 5, 1));
+
+        // A second later there's a token again.
+        let (allowed, quota) = split(bucket.take(now + Duration::from_secs(1)));
+        assert!(allowed);
+        assert_eq!(quota.remaining, 0);
+    }
+
+    #[test]
+    fn retry_after_is_at_least_a_second() {
+        // 100 tokens a second, so the next one is due in well under a second.
+        let limits = Limits { rate_limit_per_minute: 6000, rate_limit_burst: 1 };
+        let now = Instant::now();
+        let mut bucket = Bucket::new(limits, now);
+        assert!(matches!(bucket.take(now), Decision::Allowed(_)));
+        let Decision::Limited(quota) = bucket.take(now + Duration::from_millis(5)) else {
+            panic!("expected the second call to be limited")
+        };
+        assert_eq!(quota.retry_after_secs, 1);
+
+        let response = limited_response(&quota);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "1");
+        assert_eq!(response.headers()["X-RateLimit-Limit"], "1");
+        assert_eq!(response.headers()["X-RateLimit-Remaining"], "0");
+    }
+
+    #[test]
+    fn prune_drops_only_full_buckets_and_expired_unknown_keys() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new();
+        let mut used = Bucket::new(LIMITS, now);
+        used.take(now);
+        limiter.buckets.lock().unwrap().insert("full".to_string(), Bucket::new(LIMITS, now));
+        limiter.buckets.lock().unwrap().insert("used".to_string(), used);
+        limiter.unknown.lock().unwrap().insert("bogus".to_string(), now);
+
+        limiter.prune(now);
+        assert_eq!(limiter.buckets.lock().unwrap().keys().collect::<Vec<_>>(), ["used"]);
+        assert!(limiter.unknown.lock().unwrap().contains_key("bogus"));
+
+        // Refilled by now, and due another lookup.
+        limiter.prune(now + LIMITS_TTL);
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+        assert!(limiter.unknown.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_keys_are_not_looked_up_again() {
+        // Any query would fail, since the pool can't connect.
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://localhost:1/unused")
+            .unwrap();
+        let limiter = RateLimiter::new();
+        limiter.unknown.lock().unwrap().insert("bogus".to_string(), Instant::now());
+        assert!(limiter.take("bogus", &pool).await.unwrap().is_none());
+        assert!(limiter.take("other", &pool).await.is_err());
+    }
+}
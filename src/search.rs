@@ -0,0 +1,507 @@
+use axum::{
+    extract::Query,
+    extract::rejection::QueryRejection,
+    response::{IntoResponse, Response},
+    Json,
+    extract::State,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use sqlx::{
+    postgres::PgArguments,
+    query::QueryAs,
+    PgPool,
+    Postgres,
+};
+
+use crate::db::Product;
+use crate::error::{bad_request, AppError};
+
+
+// Only these columns may be sorted on. The SQL fragment for each variant is a
+// static string, so nothing the user sends ever ends up in the query text.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    #[default]
+    Relevance,
+    Name,
+    Price,
+    Rating,
+    ReviewCount,
+    Brand,
+    Seller,
+    // Only comparable within one unit, so best combined with `price_unit`.
+    PricePerUnit,
+}
+
+impl SortField {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            SortField::Relevance => "relevance",
+            SortField::Name => "name",
+            SortField::Price => "price",
+            SortField::Rating => "rating",
+            SortField::ReviewCount => "review_count",
+            SortField::Brand => "brand",
+            SortField::Seller => "seller",
+            SortField::PricePerUnit => "price_per_unit",
+        }
+    }
+
+    fn sql_type(&self) -> &'static str {
+        match self {
+            SortField::Relevance | SortField::Price | SortField::Rating | SortField::PricePerUnit => "float8",
+            SortField::ReviewCount => "int4",
+            SortField::Name | SortField::Brand | SortField::Seller => "text",
+        }
+    }
+
+    // The row's value in this column, as text for the cursor.
+    fn value_of(&self, result: &SearchResult) -> Option<String> {
+        let product = &result.product;
+        match self {
+            SortField::Relevance => Some(result.relevance.to_string()),
+            SortField::Name => Some(product.name.clone()),
+            SortField::Price => Some(product.price.to_string()),
+            SortField::Rating => product.rating.map(|rating| rating.to_string()),
+            SortField::ReviewCount => Some(product.review_count.to_string()),
+            SortField::Brand => Some(product.brand.clone()),
+            SortField::Seller => Some(product.seller.clone()),
+            SortField::PricePerUnit => product.price_per_unit.map(|price| price.to_string()),
+        }
+    }
+
+    // Used when the caller doesn't pass `order`: best matches come first.
+    pub fn default_order(&self) -> SortOrder {
+        match self {
+            SortField::Relevance => SortOrder::Desc,
+            _ => SortOrder::Asc,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+
+    // Compares a column with the cursor's value to find the rows after it.
+    fn after_op(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => ">",
+            SortOrder::Desc => "<",
+        }
+    }
+}
+
+pub const DEFAULT_LIMIT: i64 = 10;
+pub const MAX_LIMIT: i64 = 100;
+
+// Opaque to API consumers: callers should hand back whatever `next_cursor`
+// they were given rather than build one themselves. It holds the sort key of
+// the last row returned, so pages don't shift as new scrapes land, and the
+// fingerprint of the search it came from, so it can't be reused with other
+// parameters.
+#[derive(Serialize, Deserialize)]
+pub struct Cursor {
+    search: String,
+    value: Option<String>,
+    seller: String,
+    sku: i64,
+}
+
+impl Cursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(token: &str) -> Option<Cursor> {
+        let bytes = URL_SAFE_NO_PAD.decode(token).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+
+    pub fn is_for(&self, search: &str) -> bool {
+        self.search == search
+    }
+}
+
+// Identifies a search by everything that decides which rows it returns and
+// in what order. The page size isn't part of it, so that can change between
+// pages.
+pub fn search_fingerprint(query: &str, filters: &SearchFilters, sort: SortField, order: SortOrder) -> String {
+    let canonical = serde_json::to_vec(&(query, filters, sort, order)).unwrap();
+    hex::encode(&Sha256::digest(canonical)[..8])
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    query: String,
+    #[serde(default)]
+    sort: SortField,
+    order: Option<SortOrder>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    seller: Option<String>,
+    brand: Option<String>,
+    availability: Option<String>,
+    min_price: Option<f64>,
+    max_price: Option<f64>,
+    min_rating: Option<f64>,
+    min_review_count: Option<i32>,
+    price_unit: Option<String>,
+    max_price_per_unit: Option<f64>,
+}
+
+// Applied to each listing's latest scrape, after text matching.
+#[derive(Serialize, Default)]
+pub struct SearchFilters {
+    pub seller: Option<String>,
+    pub brand: Option<String>,
+    // Either the full schema.org URL as stored, or just e.g. "InStock".
+    pub availability: Option<String>,
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    pub min_rating: Option<f64>,
+    pub min_review_count: Option<i32>,
+    // "kg", "l" or "each"
+    pub price_unit: Option<String>,
+    pub max_price_per_unit: Option<f64>,
+}
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct SearchResult {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub product: Product,
+    pub relevance: f64,
+}
+
+#[derive(sqlx::FromRow)]
+struct SearchRow {
+    #[sqlx(flatten)]
+    result: SearchResult,
+    total: i64,
+}
+
+pub struct SearchPage {
+    pub results: Vec<SearchResult>,
+    pub total_estimate: i64,
+    pub next_cursor: Option<Cursor>,
+}
+
+#[derive(sqlx::FromRow)]
+struct FacetRow {
+    facet: String,
+    value: String,
+    count: i64,
+}
+
+#[derive(Serialize)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: i64,
+}
+
+#[derive(Serialize, Default)]
+pub struct Facets {
+    pub seller: Vec<FacetCount>,
+    pub brand: Vec<FacetCount>,
+    pub availability: Vec<FacetCount>,
+}
+
+#[derive(Serialize)]
+pub struct SearchResponse {
+    results: Vec<SearchResult>,
+    next_cursor: Option<String>,
+    total_estimate: i64,
+    facets: Facets,
+}
+
+
+// Latest scrape of every listing matching the search text ($1), with one
+// flag per filter so facet counts can leave out their own filter.
+// $2..$10 are the fields of `SearchFilters`, in declaration order.
+const MATCHED_SQL: &str = "
+    matched AS (
+        SELECT *,
+            ($2::text IS NULL OR seller = $2) AS seller_ok,
+            ($3::text IS NULL OR brand = $3) AS brand_ok,
+            ($4::text IS NULL OR availability = $4 OR availability = 'https://schema.org/' || $4) AS availability_ok,
+            ($5::float8 IS NULL OR price >= $5)
+                AND ($6::float8 IS NULL OR price <= $6)
+                AND ($7::float8 IS NULL OR rating >= $7)
+                AND ($8::int IS NULL OR review_count >= $8)
+                AND ($9::text IS NULL OR price_unit = $9)
+                AND ($10::float8 IS NULL OR price_per_unit <= $10) AS rest_ok
+        FROM (
+            SELECT DISTINCT ON (seller, sku) gtin, name, sku, image, description, rating, review_count, brand, price, url, availability, seller, price_per_unit, price_unit,
+                (ts_rank(search_vector, websearch_to_tsquery('english', $1)) + word_similarity($1, name))::float8 AS relevance
+            FROM product
+            WHERE $1 = ''
+                OR search_vector @@ websearch_to_tsquery('english', $1)
+                OR $1 <% name
+            ORDER BY seller, sku, scraped DESC
+        ) latest
+    )";
+
+
+fn bind_filters<'q, O>(
+    query: QueryAs<'q, Postgres, O, PgArguments>,
+    filters: &'q SearchFilters,
+) -> QueryAs<'q, Postgres, O, PgArguments> {
+    query
+        .bind(&filters.seller)
+        .bind(&filters.brand)
+        .bind(&filters.availability)
+        .bind(filters.min_price)
+        .bind(filters.max_price)
+        .bind(filters.min_rating)
+        .bind(filters.min_review_count)
+        .bind(&filters.price_unit)
+        .bind(filters.max_price_per_unit)
+}
+
+
+pub async fn search_for_product(
+    query: String,
+    filters: &SearchFilters,
+    sort: SortField,
+    order: SortOrder,
+    limit: i64,
+    cursor: Option<&Cursor>,
+    pool: PgPool,
+) -> Result<SearchPage, sqlx::Error> {
+    let sort_sql = sort.as_sql();
+    let sort_type = sort.sql_type();
+    let order_sql = order.as_sql();
+    let after_op = order.after_op();
+    let limit = limit.clamp(1, MAX_LIMIT);
+    // Rows after the cursor in the ORDER BY below, where NULLs come last
+    // whichever way the column is sorted. $12..$14 are the cursor's value,
+    // seller and sku.
+    let after_sql = match cursor {
+        None => "TRUE".to_string(),
+        Some(Cursor { value: None, .. }) => format!("({sort_sql} IS NULL AND (seller, sku) > ($13, $14))"),
+        Some(Cursor { value: Some(_), .. }) => format!(
+            "({sort_sql} {after_op} $12::{sort_type}
+                OR ({sort_sql} = $12::{sort_type} AND (seller, sku) > ($13, $14))
+                OR {sort_sql} IS NULL)"
+        ),
+    };
+    let sql = format!(
+        "WITH {MATCHED_SQL}
+        SELECT * FROM (
+            SELECT *, COUNT(*) OVER () AS total FROM matched
+            WHERE seller_ok AND brand_ok AND availability_ok AND rest_ok
+        ) filtered
+        WHERE {after_sql}
+        ORDER BY {sort_sql} {order_sql} NULLS LAST, seller, sku
+        LIMIT $11"
+    );
+    // One extra row says whether there's another page.
+    let mut rows: Vec<SearchRow> = bind_filters(sqlx::query_as(&sql).bind(&query), filters)
+        .bind(limit + 1)
+        .bind(cursor.and_then(|cursor| cursor.value.clone()))
+        .bind(cursor.map(|cursor| cursor.seller.clone()))
+        .bind(cursor.map(|cursor| cursor.sku))
+        .fetch_all(&pool).await?;
+
+    let total_estimate = rows.first().map_or(0, |row| row.total);
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    let next_cursor = rows.last().filter(|_| has_more).map(|row| Cursor {
+        search: search_fingerprint(&query, filters, sort, order),
+        value: sort.value_of(&row.result),
+        seller: row.result.product.seller.clone(),
+        sku: row.result.product.sku,
+    });
+    Ok(SearchPage {
+        results: rows.into_iter().map(|row| row.result).collect(),
+        total_estimate,
+        next_cursor,
+    })
+}
+
+
+// Each facet is counted with every filter applied except its own, so a
+// sidebar can still offer the other sellers while one is selected.
+pub async fn search_facets(query: String, filters: &SearchFilters, pool: PgPool) -> Result<Facets, sqlx::Error> {
+    let sql = format!(
+        "WITH {MATCHED_SQL}
+        SELECT 'seller' AS facet, seller AS value, COUNT(*) AS count FROM matched
+        WHERE brand_ok AND availability_ok AND rest_ok GROUP BY seller
+        UNION ALL
+        SELECT 'brand', brand, COUNT(*) FROM matched
+        WHERE seller_ok AND availability_ok AND rest_ok GROUP BY brand
+        UNION ALL
+        SELECT 'availability', availability, COUNT(*) FROM matched
+        WHERE seller_ok AND brand_ok AND rest_ok GROUP BY availability
+        ORDER BY facet, count DESC, value"
+    );
+    let rows: Vec<FacetRow> = bind_filters(sqlx::query_as(&sql).bind(query), filters)
+        .fetch_all(&pool).await?;
+
+    let mut facets = Facets::default();
+    for row in rows {
+        let counts = match row.facet.as_str() {
+            "seller" => &mut facets.seller,
+            "brand" => &mut facets.brand,
+            _ => &mut facets.availability,
+        };
+        counts.push(FacetCount { value: row.value, count: row.count });
+    }
+    Ok(facets)
+}
+
+
+pub async fn search(params: Result<Query<SearchParams>, QueryRejection>, State(pool): State<PgPool>) -> Response {
+    let Query(params) = match params {
+        Ok(params) => params,
+        Err(rejection) => return bad_request(rejection.body_text()),
+    };
+    let order = params.order.unwrap_or(params.sort.default_order());
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    let filters = SearchFilters {
+        seller: params.seller,
+        brand: params.brand,
+        availability: params.availability,
+        min_price: params.min_price,
+        max_price: params.max_price,
+        min_rating: params.min_rating,
+        min_review_count: params.min_review_count,
+        price_unit: params.price_unit,
+        max_price_per_unit: params.max_price_per_unit,
+    };
+    let search = search_fingerprint(&params.query, &filters, params.sort, order);
+    let cursor = match params.cursor.as_deref().map(Cursor::decode) {
+        None => None,
+        Some(Some(cursor)) if cursor.is_for(&search) => Some(cursor),
+        Some(Some(_)) => return bad_request("cursor belongs to a different search; repeat the search without it".to_string()),
+        Some(None) => return bad_request("Invalid cursor".to_string()),
+    };
+    let page = search_for_product(params.query.clone(), &filters, params.sort, order, limit, cursor.as_ref(), pool.clone()).await;
+    let facets = search_facets(params.query, &filters, pool).await;
+
+    match (page, facets) {
+        (Ok(page), Ok(facets)) => Json(SearchResponse {
+            results: page.results,
+            next_cursor: page.next_cursor.map(|cursor| cursor.encode()),
+            total_estimate: page.total_estimate,
+            facets,
+        }).into_response(),
+        (Err(err), _) | (_, Err(err)) => AppError::from(err).into_response(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::{StatusCode, Uri}};
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::*;
+
+    // Every variant, spelled out so a new one fails to compile here until
+    // it's added to the tests.
+    fn all_sort_fields() -> Vec<SortField> {
+        let all = vec![
+            SortField::Relevance,
+            SortField::Name,
+            SortField::Price,
+            SortField::Rating,
+            SortField::ReviewCount,
+            SortField::Brand,
+            SortField::Seller,
+            SortField::PricePerUnit,
+        ];
+        for field in &all {
+            match field {
+                SortField::Relevance | SortField::Name | SortField::Price | SortField::Rating
+                | SortField::ReviewCount | SortField::Brand | SortField::Seller | SortField::PricePerUnit => {}
+            }
+        }
+        all
+    }
+
+    // Calls the search handler with a raw query string. Only for requests it
+    // turns away while parsing the sort, order and cursor, since the pool
+    // can't connect.
+    async fn search_with(query: &str) -> (StatusCode, serde_json::Value) {
+        let pool = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+        let uri: Uri = format!("/api/products/search?{query}").parse().unwrap();
+        let response = search(Query::try_from_uri(&uri), State(pool)).await;
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn sort_sql_is_a_static_column_name() {
+        let columns = ["relevance", "name", "price", "rating", "review_count", "brand", "seller", "price_per_unit"];
+        for field in all_sort_fields() {
+            let sql = field.as_sql();
+            assert!(columns.contains(&sql), "{sql} is not a known column");
+            assert!(sql.chars().all(|c| c.is_ascii_lowercase() || c == '_'), "{sql} isn't a plain identifier");
+        }
+        assert_eq!(SortOrder::Asc.as_sql(), "ASC");
+        assert_eq!(SortOrder::Desc.as_sql(), "DESC");
+    }
+
+    #[tokio::test]
+    async fn injection_payloads_are_rejected() {
+        for query in [
+            "query=milk&sort=name%3BDROP%20TABLE%20product",
+            "query=milk&sort=price%20desc",
+            "query=milk&order=asc--",
+            "query=milk&sort=password",
+            "query=milk&sort=",
+            "query=milk&order=sideways",
+        ] {
+            let (status, body) = search_with(query).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{query}");
+            assert_eq!(body["code"], "bad_request", "{query}");
+            assert!(body["message"].is_string(), "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn cursor_from_another_search_is_rejected() {
+        let filters = SearchFilters::default();
+        let cursor = Cursor {
+            search: search_fingerprint("milk", &filters, SortField::Price, SortOrder::Asc),
+            value: Some("1.5".to_string()),
+            seller: "tesco".to_string(),
+            sku: 1,
+        };
+        let token = cursor.encode();
+        for query in [
+            format!("query=bread&sort=price&cursor={token}"),
+            format!("query=milk&sort=price&order=desc&cursor={token}"),
+            format!("query=milk&sort=name&cursor={token}"),
+            format!("query=milk&sort=price&seller=asda&cursor={token}"),
+        ] {
+            let (status, body) = search_with(&query).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{query}");
+            assert_eq!(body["code"], "bad_request", "{query}");
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_cursor_is_rejected() {
+        let (status, body) = search_with("query=milk&cursor=not-a-cursor").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "bad_request");
+    }
+}
@@ -0,0 +1,73 @@
+use async_trait::async_trait;
+use axum_login::tower_sessions::{
+    session::Id,
+    Session,
+    SessionStore,
+};
+use sqlx::{types::Json, PgPool};
+use tokio::time::{interval, Duration};
+
+
+// Session store backed by the `session` table, on the app's own pool.
+#[derive(Clone, Debug)]
+pub struct PgSessionStore {
+    pool: PgPool,
+}
+
+impl PgSessionStore {
+    pub fn new(pool: PgPool) -> Self {
+        PgSessionStore { pool }
+    }
+}
+
+#[async_trait]
+impl SessionStore for PgSessionStore {
+    type Error = sqlx::Error;
+
+    async fn save(&self, session: &Session) -> Result<(), Self::Error> {
+        sqlx::query(
+            "INSERT INTO session (id, data, expires_at)
+            VALUES ($1, $2, to_timestamp($3))
+            ON CONFLICT (id) DO UPDATE SET data = EXCLUDED.data, expires_at = EXCLUDED.expires_at"
+        )
+        .bind(session.id().0)
+        .bind(Json(session))
+        .bind(session.expiry_date().unix_timestamp() as f64)
+        .execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn load(&self, session_id: &Id) -> Result<Option<Session>, Self::Error> {
+        let session: Option<Json<Session>> = sqlx::query_scalar("SELECT data FROM session WHERE id = $1 AND expires_at > NOW()")
+            .bind(session_id.0)
+            .fetch_optional(&self.pool).await?;
+        Ok(session.map(|Json(session)| session))
+    }
+
+    async fn delete(&self, session_id: &Id) -> Result<(), Self::Error> {
+        sqlx::query("DELETE FROM session WHERE id = $1")
+            .bind(session_id.0)
+            .execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+
+// Expired sessions are never loaded, this just stops them piling up.
+pub async fn delete_expired(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM session WHERE expires_at <= NOW()")
+        .execute(pool).await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn run_periodically(pool: PgPool, every: Duration) {
+    let mut ticker = interval(every);
+    loop {
+        ticker.tick().await;
+        match delete_expired(&pool).await {
+            Ok(0) => {}
+            Ok(deleted) => tracing::info!("deleted {} expired sessions", deleted),
+            Err(err) => tracing::error!("expired session cleanup failed: {}", err),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+
+use axum::extract::FromRef;
+use sqlx::PgPool;
+
+use crate::config::Config;
+use crate::ratelimit::RateLimiter;
+
+
+// Shared by every request. Handlers take only the part they need, e.g.
+// `State(pool): State<PgPool>`.
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: PgPool,
+    pub rate_limiter: RateLimiter,
+    pub config: Arc<Config>,
+}
+
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.pool.clone()
+    }
+}
+
+impl FromRef<AppState> for RateLimiter {
+    fn from_ref(state: &AppState) -> Self {
+        state.rate_limiter.clone()
+    }
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
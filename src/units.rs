@@ -0,0 +1,298 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{types::Json, PgPool};
+use tokio::time::{interval, Duration};
+
+use crate::db::{get_watermark, set_watermark};
+
+const WATERMARK_JOB: &str = "unit_prices";
+const BATCH_SIZE: i64 = 5000;
+
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    Grams,
+    Millilitres,
+    Each,
+}
+
+// A pack size in base units, e.g. "6 x 330ml" is 6 items of 330 millilitres.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PackSize {
+    pub count: f64,
+    pub amount: f64,
+    pub unit: Unit,
+}
+
+impl PackSize {
+    pub fn total(&self) -> f64 {
+        self.count * self.amount
+    }
+
+    // Shelf price per kg, per litre or per item.
+    pub fn unit_price(&self, price: f64) -> (f64, &'static str) {
+        match self.unit {
+            Unit::Grams => (price / (self.total() / 1000.0), "kg"),
+            Unit::Millilitres => (price / (self.total() / 1000.0), "l"),
+            Unit::Each => (price / self.total(), "each"),
+        }
+    }
+
+    // Listings round pack sizes differently ("2.27L" vs "2.272L", "4 pints"),
+    // so sizes within 2% of each other count as the same.
+    pub fn is_compatible(&self, other: &PackSize) -> bool {
+        let (a, b) = (self.total(), other.total());
+        self.unit == other.unit && (a - b).abs() <= 0.02 * a.max(b)
+    }
+}
+
+
+fn pack_size_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(
+        r"(?i)(?:\b(\d+)\s*[x×]\s*|\b)(\d+(?:\.\d+)?)\s*(kg|g|ml|cl|l|ltr|litres?|liters?|pints?|pk|packs?)\b"
+    ).unwrap())
+}
+
+// "Pack of 12", "pack of 6" - counts with the unit in front.
+fn pack_of_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"(?i)\bpack\s+of\s+(\d+)\b").unwrap())
+}
+
+// Finds the first pack size mentioned in `text`.
+pub fn parse_pack_size(text: &str) -> Option<PackSize> {
+    let Some(captures) = pack_size_regex().captures(text) else {
+        let count: f64 = pack_of_regex().captures(text)?[1].parse().ok()?;
+        return (count > 0.0).then_some(PackSize { count, amount: 1.0, unit: Unit::Each })
+    };
+    let count: f64 = captures.get(1).map_or(Some(1.0), |m| m.as_str().parse().ok())?;
+    let amount: f64 = captures[2].parse().ok()?;
+    let (scale, unit) = match captures[3].to_lowercase().as_str() {
+        "kg" => (1000.0, Unit::Grams),
+        "g" => (1.0, Unit::Grams),
+        "ml" => (1.0, Unit::Millilitres),
+        "cl" => (10.0, Unit::Millilitres),
+        "l" | "ltr" | "litre" | "litres" | "liter" | "liters" => (1000.0, Unit::Millilitres),
+        "pint" | "pints" => (568.261, Unit::Millilitres),
+        _ => (1.0, Unit::Each),
+    };
+    if count <= 0.0 || amount <= 0.0 {
+        return None
+    }
+    Some(PackSize { count, amount: amount * scale, unit })
+}
+
+
+// Blanks out every pack size in `text`, so name comparisons can ignore them.
+pub fn strip_pack_sizes(text: &str) -> String {
+    let text = pack_size_regex().replace_all(text, " ");
+    pack_of_regex().replace_all(&text, " ").into_owned()
+}
+
+
+// JSON-LD sizes are either free text ("500g") or a QuantitativeValue with a
+// UN/CEFACT unit code.
+fn pack_size_from_json_ld(json_ld: &Value) -> Option<PackSize> {
+    ["size", "weight"].iter().find_map(|key| match json_ld.get(key)? {
+        Value::String(text) => parse_pack_size(text),
+        Value::Object(quantity) => {
+            let value = quantity.get("value").and_then(|v| v.as_f64().or_else(|| v.as_str()?.parse().ok()))?;
+            let unit = match quantity.get("unitCode").or(quantity.get("unitText"))?.as_str()? {
+                "GRM" => "g",
+                "KGM" => "kg",
+                "MLT" => "ml",
+                "LTR" => "l",
+                "CLT" => "cl",
+                "H87" | "C62" => "pack",
+                other => other,
+            };
+            parse_pack_size(&format!("{value}{unit}"))
+        }
+        _ => None,
+    })
+}
+
+// The name is the most reliable source, then the structured data, then the
+// free-text description.
+pub fn listing_pack_size(name: &str, description: &str, json_ld: Option<&Value>) -> Option<PackSize> {
+    parse_pack_size(name)
+        .or_else(|| json_ld.and_then(pack_size_from_json_ld))
+        .or_else(|| parse_pack_size(description))
+}
+
+
+#[derive(sqlx::FromRow)]
+struct UnpricedRow {
+    id: i32,
+    price: f64,
+    name: String,
+    description: String,
+    json_ld: Option<Json<Value>>,
+}
+
+// Fills in price_per_unit for rows scraped since the last run. Returns how
+// many rows were looked at.
+pub async fn fill_unit_prices(pool: &PgPool) -> Result<usize, sqlx::Error> {
+    let mut processed = 0;
+    loop {
+        let mut tx = pool.begin().await?;
+        let last_id = get_watermark(&mut *tx, WATERMARK_JOB).await?;
+        let rows: Vec<UnpricedRow> = sqlx::query_as(
+            "SELECT id, price, name, description, json_ld FROM product
+            WHERE id > $1
+            ORDER BY id
+            LIMIT $2"
+        )
+        .bind(last_id)
+        .bind(BATCH_SIZE)
+        .fetch_all(&mut *tx).await?;
+        let Some(last_row) = rows.last() else {
+            return Ok(processed)
+        };
+        let next_id = last_row.id as i64;
+
+        let (mut ids, mut unit_prices, mut units) = (Vec::new(), Vec::new(), Vec::new());
+        for row in &rows {
+            let pack = listing_pack_size(&row.name, &row.description, row.json_ld.as_ref().map(|json| &json.0));
+            if let Some(pack) = pack.filter(|_| row.price > 0.0) {
+                let (unit_price, unit) = pack.unit_price(row.price);
+                ids.push(row.id);
+                unit_prices.push(unit_price);
+                units.push(unit);
+            }
+        }
+        sqlx::query(
+            "UPDATE product SET price_per_unit = unit_prices.price_per_unit, price_unit = unit_prices.price_unit
+            FROM UNNEST($1::int[], $2::float8[], $3::text[]) AS unit_prices (id, price_per_unit, price_unit)
+            WHERE product.id = unit_prices.id"
+        )
+        .bind(ids)
+        .bind(unit_prices)
+        .bind(units)
+        .execute(&mut *tx).await?;
+        set_watermark(&mut *tx, WATERMARK_JOB, next_id).await?;
+        tx.commit().await?;
+        processed += rows.len();
+    }
+}
+
+
+pub async fn run_periodically(pool: PgPool, every: Duration) {
+    let mut ticker = interval(every);
+    loop {
+        ticker.tick().await;
+        match fill_unit_prices(&pool).await {
+            Ok(0) => {}
+            Ok(processed) => tracing::info!("unit prices filled for {} rows", processed),
+            Err(err) => tracing::error!("unit price job failed: {}", err),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn pack(count: f64, amount: f64, unit: Unit) -> Option<PackSize> {
+        Some(PackSize { count, amount, unit })
+    }
+
+    fn assert_pack_eq(input: &str, actual: Option<PackSize>, expected: Option<PackSize>) {
+        match (actual, expected) {
+            (Some(actual), Some(expected)) => {
+                assert_eq!(actual.unit, expected.unit, "{input}");
+                assert_eq!(actual.count, expected.count, "{input}");
+                assert!((actual.amount - expected.amount).abs() < 1e-9, "{input}: {} != {}", actual.amount, expected.amount);
+            }
+            (actual, expected) => assert_eq!(actual, expected, "{input}"),
+        }
+    }
+
+    #[test]
+    fn parses_pack_sizes_from_text() {
+        let cases = [
+            ("Penne Pasta 500g", pack(1.0, 500.0, Unit::Grams)),
+            ("Potatoes 2.5kg", pack(1.0, 2500.0, Unit::Grams)),
+            ("Cola 6 x 330ml", pack(6.0, 330.0, Unit::Millilitres)),
+            ("Cola 6x330ML", pack(6.0, 330.0, Unit::Millilitres)),
+            ("Baked Beans 4 × 415g", pack(4.0, 415.0, Unit::Grams)),
+            ("Wine 75cl", pack(1.0, 750.0, Unit::Millilitres)),
+            ("Semi Skimmed Milk 2.27L", pack(1.0, 2270.0, Unit::Millilitres)),
+            ("Orange Juice 1 Litre", pack(1.0, 1000.0, Unit::Millilitres)),
+            ("Whole Milk 4 Pints", pack(1.0, 4.0 * 568.261, Unit::Millilitres)),
+            ("Whole Milk 1 pint", pack(1.0, 568.261, Unit::Millilitres)),
+            ("Yoghurts 4 pack", pack(1.0, 4.0, Unit::Each)),
+            ("Eggs 12pk", pack(1.0, 12.0, Unit::Each)),
+            ("Toilet Roll Pack of 9", pack(9.0, 1.0, Unit::Each)),
+            ("Bananas", None),
+            ("Pasta 0g", None),
+            ("Pack of 0", None),
+        ];
+        for (input, expected) in cases {
+            assert_pack_eq(input, parse_pack_size(input), expected);
+        }
+    }
+
+    #[test]
+    fn parses_pack_sizes_from_json_ld() {
+        let cases = [
+            (json!({ "size": "500g" }), pack(1.0, 500.0, Unit::Grams)),
+            (json!({ "weight": "1.5kg" }), pack(1.0, 1500.0, Unit::Grams)),
+            (json!({ "weight": { "value": 400, "unitCode": "GRM" } }), pack(1.0, 400.0, Unit::Grams)),
+            (json!({ "weight": { "value": "1", "unitCode": "KGM" } }), pack(1.0, 1000.0, Unit::Grams)),
+            (json!({ "size": { "value": 75, "unitCode": "CLT" } }), pack(1.0, 750.0, Unit::Millilitres)),
+            (json!({ "size": { "value": 2, "unitCode": "LTR" } }), pack(1.0, 2000.0, Unit::Millilitres)),
+            (json!({ "size": { "value": 6, "unitCode": "H87" } }), pack(1.0, 6.0, Unit::Each)),
+            (json!({ "size": { "value": 330, "unitText": "ml" } }), pack(1.0, 330.0, Unit::Millilitres)),
+            // `size` wins over `weight` when both are there.
+            (json!({ "size": "250ml", "weight": "300g" }), pack(1.0, 250.0, Unit::Millilitres)),
+            (json!({ "size": "large" }), None),
+            (json!({ "size": { "value": 3, "unitCode": "XYZ" } }), None),
+            (json!({ "name": "Milk" }), None),
+        ];
+        for (json_ld, expected) in cases {
+            assert_pack_eq(&json_ld.to_string(), pack_size_from_json_ld(&json_ld), expected);
+        }
+    }
+
+    #[test]
+    fn name_beats_json_ld_beats_description() {
+        let json_ld = json!({ "size": "1kg" });
+        assert_eq!(listing_pack_size("Rice 500g", "Rice 2kg", Some(&json_ld)).map(|p| p.total()), Some(500.0));
+        assert_eq!(listing_pack_size("Rice", "Rice 2kg", Some(&json_ld)).map(|p| p.total()), Some(1000.0));
+        assert_eq!(listing_pack_size("Rice", "Rice 2kg", None).map(|p| p.total()), Some(2000.0));
+    }
+
+    #[test]
+    fn sizes_within_two_percent_are_compatible() {
+        let cases = [
+            ("2.27L", "2.272L", true),
+            ("4 pints", "2.27L", true),
+            ("500g", "0.5kg", true),
+            ("1000g", "980g", true),
+            ("1000g", "979g", false),
+            ("500g", "1kg", false),
+            ("6 x 330ml", "1.98L", true),
+            ("500ml", "500g", false),
+            ("4 pack", "pack of 4", true),
+        ];
+        for (a, b, compatible) in cases {
+            let (a_pack, b_pack) = (parse_pack_size(a).unwrap(), parse_pack_size(b).unwrap());
+            assert_eq!(a_pack.is_compatible(&b_pack), compatible, "{a} vs {b}");
+            assert_eq!(b_pack.is_compatible(&a_pack), compatible, "{b} vs {a}");
+        }
+    }
+
+    #[test]
+    fn strips_pack_sizes_from_names() {
+        assert_eq!(strip_pack_sizes("Cola 6 x 330ml Pack of 6").split_whitespace().collect::<Vec<_>>(), ["Cola"]);
+    }
+}
@@ -0,0 +1,173 @@
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+    extract::State,
+    Extension,
+};
+use chrono::{NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::db::CreditsPeriod;
+use crate::error::AppError;
+use crate::plans;
+
+
+// Who an API request was charged to. The API-key middleware adds this to
+// the request's extensions once the call has been paid for.
+#[derive(Clone)]
+pub struct ApiCaller {
+    pub key_id: i64,
+    pub users_id: i64,
+    pub credits_period_id: i64,
+    // Credits left to this key after this call, counting both the period
+    // allowance and the key's own cap.
+    pub credits_remaining: i64,
+}
+
+pub enum Charge {
+    Charged(ApiCaller),
+    UnknownKey,
+    PeriodExhausted,
+    KeyCapReached,
+}
+
+#[derive(sqlx::FromRow)]
+struct ChargedKey {
+    id: i64,
+    users_id: i64,
+    credit_cap: Option<i32>,
+}
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct KeyUsage {
+    pub key_id: i64,
+    pub label: String,
+    pub prefix: String,
+    pub credit_cap: Option<i32>,
+    pub calls: i64,
+}
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct EndpointUsage {
+    pub key_id: i64,
+    pub endpoint: String,
+    pub day: NaiveDate,
+    pub calls: i32,
+}
+
+#[derive(Serialize)]
+pub struct UsageResponse {
+    // The key this request was made with.
+    pub key_id: i64,
+    pub plan: String,
+    pub period: CreditsPeriod,
+    pub keys: Vec<KeyUsage>,
+    pub usage: Vec<EndpointUsage>,
+}
+
+
+// Charges one credit for a call to `endpoint` against both the user's
+// current credits period and the key's own usage. Calls that are turned
+// away aren't charged. The period row is locked for the duration, so
+// concurrent calls can't overspend it. The first call after a period ends
+// starts the next one.
+pub async fn charge(key_hash: &str, endpoint: &str, pool: &PgPool) -> Result<Charge, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let key: Option<ChargedKey> = sqlx::query_as(
+        "UPDATE api_key SET last_used_at = NOW()
+        WHERE key_hash = $1 AND revoked_at IS NULL
+        RETURNING id, users_id, credit_cap"
+    )
+    .bind(key_hash)
+    .fetch_optional(&mut *tx).await?;
+    let Some(key) = key else {
+        return Ok(Charge::UnknownKey)
+    };
+
+    let today = Utc::now().date_naive();
+    let period = plans::current_period(&mut tx, key.users_id, today).await?;
+    let charge = if period.credits_used >= period.credits_allocated {
+        Charge::PeriodExhausted
+    } else {
+        let key_calls: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(calls), 0)::int8 FROM api_usage WHERE api_key_id = $1 AND credits_period_id = $2"
+        )
+        .bind(key.id)
+        .bind(period.id)
+        .fetch_one(&mut *tx).await?;
+        if key.credit_cap.is_some_and(|cap| key_calls >= cap as i64) {
+            Charge::KeyCapReached
+        } else {
+            sqlx::query("UPDATE credits_period SET credits_used = credits_used + 1 WHERE id = $1")
+                .bind(period.id)
+                .execute(&mut *tx).await?;
+            sqlx::query("UPDATE api_key SET calls_made = calls_made + 1 WHERE id = $1")
+                .bind(key.id)
+                .execute(&mut *tx).await?;
+            sqlx::query(
+                "INSERT INTO api_usage (api_key_id, credits_period_id, endpoint, day, calls)
+                VALUES ($1, $2, $3, $4, 1)
+                ON CONFLICT (credits_period_id, api_key_id, endpoint, day) DO UPDATE SET calls = api_usage.calls + 1"
+            )
+            .bind(key.id)
+            .bind(period.id)
+            .bind(endpoint)
+            .bind(today)
+            .execute(&mut *tx).await?;
+            let period_remaining = (period.credits_allocated - period.credits_used - 1) as i64;
+            let credits_remaining = match key.credit_cap {
+                Some(cap) => period_remaining.min(cap as i64 - key_calls - 1),
+                None => period_remaining,
+            };
+            Charge::Charged(ApiCaller {
+                key_id: key.id,
+                users_id: key.users_id,
+                credits_period_id: period.id,
+                credits_remaining,
+            })
+        }
+    };
+    tx.commit().await?;
+    Ok(charge)
+}
+
+
+pub async fn period_usage(caller: &ApiCaller, pool: &PgPool) -> Result<UsageResponse, sqlx::Error> {
+    let plan: String = sqlx::query_scalar("SELECT plan FROM users WHERE id = $1")
+        .bind(caller.users_id)
+        .fetch_one(pool).await?;
+    let period: CreditsPeriod = sqlx::query_as("SELECT * FROM credits_period WHERE id = $1")
+        .bind(caller.credits_period_id)
+        .fetch_one(pool).await?;
+    let keys: Vec<KeyUsage> = sqlx::query_as(
+        "SELECT api_key.id AS key_id, api_key.label, api_key.prefix, api_key.credit_cap,
+            COALESCE(SUM(api_usage.calls), 0)::int8 AS calls
+        FROM api_key
+        LEFT JOIN api_usage ON api_usage.api_key_id = api_key.id AND api_usage.credits_period_id = $2
+        WHERE api_key.users_id = $1
+        GROUP BY api_key.id
+        ORDER BY api_key.id"
+    )
+    .bind(caller.users_id)
+    .bind(caller.credits_period_id)
+    .fetch_all(pool).await?;
+    let usage: Vec<EndpointUsage> = sqlx::query_as(
+        "SELECT api_key_id AS key_id, endpoint, day, calls
+        FROM api_usage
+        WHERE credits_period_id = $1
+        ORDER BY day, api_key_id, endpoint"
+    )
+    .bind(caller.credits_period_id)
+    .fetch_all(pool).await?;
+    Ok(UsageResponse { key_id: caller.key_id, plan, period, keys, usage })
+}
+
+// Usage by key, endpoint and day for the caller's current credits period,
+// across all of the caller's keys.
+pub async fn usage(Extension(caller): Extension<ApiCaller>, State(pool): State<PgPool>) -> Response {
+    match period_usage(&caller, &pool).await {
+        Err(err) => AppError::from(err).into_response(),
+        Ok(usage) => Json(usage).into_response(),
+    }
+}